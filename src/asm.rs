@@ -0,0 +1,581 @@
+//! An assembler that paints a program of Piet commands as codels, one command per line:
+//!
+//! ```text
+//! # Counts down from 5
+//!     push 5
+//! loop:
+//!     dup
+//!     outn
+//!     push 1
+//!     sub
+//!     dup
+//!     jnz loop
+//!     pop
+//! ```
+//!
+//! Commands are named like `OpCode`s, in lowercase, except for `nop`, which can't be painted.
+//! `push` takes an integer or a character in single quotes like `'A'`, and may push any value
+//! that fits in 32 bits, since values that can't be painted as a single block are built up with
+//! arithmetic. Everything after a `#` is a comment.
+//!
+//! A line like `loop:` defines a label, which `jmp` jumps to. `jz` and `jnz` pop a value and jump
+//! if it's zero or not zero, and carry on with the next line otherwise. `halt` stops the program,
+//! as does running off its end. `ptr` and `swtch` are not allowed, because turning anywhere but
+//! at a branch would steer the PC off its path.
+//!
+//! Every stretch of code between labels and jumps is laid out left to right along a lane of its
+//! own, and lanes are stacked top to bottom. Jumps are routed through white, see `assemble`.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::ops::Range;
+
+use crate::blocks::{Hue, Lightness, Type};
+use crate::cpu::OpCode;
+
+const HUES: [Hue; 6] = [Hue::Red, Hue::Yellow, Hue::Green, Hue::Cyan, Hue::Blue, Hue::Magenta];
+const LIGHTNESSES: [Lightness; 3] = [Lightness::Light, Lightness::Normal, Lightness::Dark];
+
+/// The largest value pushed by painting a single block. Larger values are built with arithmetic,
+/// which keeps blocks small.
+const MAX_PUSH: i64 = 64;
+
+/// The color every lane starts with, and that every branch starts from.
+const START: Type = Type::Color(Lightness::Light, Hue::Red);
+
+/// How a lane hands over control once its commands are done. Jumps go to lanes by label while
+/// parsing, and by index once the labels are resolved.
+#[derive(Debug, Clone)]
+enum End<L> {
+    /// Halts the program
+    Halt,
+    /// Carries on at the start of another lane
+    Jump(L),
+    /// Pops a value, which is 0 or 1, and carries on at the start of another lane if it's 1, or
+    /// of the next lane if it's 0
+    Branch(L),
+}
+
+/// A stretch of straight-line code, as pairs of a command and the size of the block that
+/// executes it.
+#[derive(Debug, Clone)]
+struct Lane<L> {
+    steps: Vec<(OpCode, usize)>,
+    end: End<L>,
+}
+
+fn invalid(line: usize, msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+fn parse_op(name: &str) -> Option<OpCode> {
+    Some(match name {
+        "push" => OpCode::PUSH,
+        "pop" => OpCode::POP,
+        "add" => OpCode::ADD,
+        "sub" => OpCode::SUB,
+        "mul" => OpCode::MUL,
+        "div" => OpCode::DIV,
+        "mod" => OpCode::MOD,
+        "not" => OpCode::NOT,
+        "gt" => OpCode::GT,
+        "ptr" => OpCode::PTR,
+        "swtch" => OpCode::SWTCH,
+        "dup" => OpCode::DUP,
+        "roll" => OpCode::ROLL,
+        "inpn" => OpCode::INPN,
+        "inpc" => OpCode::INPC,
+        "outn" => OpCode::OUTN,
+        "outc" => OpCode::OUTC,
+        _ => return None,
+    })
+}
+
+/// Parses the argument of `push`, either an integer or a character in single quotes.
+fn parse_value(arg: &str) -> Option<i64> {
+    let mut chars = arg.chars();
+    if chars.next() == Some('\'') && chars.next_back() == Some('\'') {
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(i64::from(u32::from(c))),
+            _ => None,
+        };
+    }
+    arg.parse().ok()
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Cuts off the comment at the end of a line, unless the `#` is a quoted character.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Adds the commands that push `n` to `steps`, as pairs of a command and the size of the block
+/// that executes it.
+fn push(n: i64, steps: &mut Vec<(OpCode, usize)>) {
+    if n == 0 {
+        steps.push((OpCode::PUSH, 1));
+        steps.push((OpCode::NOT, 1));
+    } else if n == i64::from(i32::MIN) {
+        // -n doesn't fit in 32 bits, so build n + 1 and subtract 1
+        push(n + 1, steps);
+        push(1, steps);
+        steps.push((OpCode::SUB, 1));
+    } else if n < 0 {
+        push(0, steps);
+        push(-n, steps);
+        steps.push((OpCode::SUB, 1));
+    } else if n <= MAX_PUSH {
+        steps.push((OpCode::PUSH, n as usize));
+    } else {
+        // n = a * b + c, where a and b are about the square root of n
+        let a = (n as f64).sqrt() as i64;
+        let (b, c) = (n / a, n % a);
+        push(a, steps);
+        push(b, steps);
+        steps.push((OpCode::MUL, 1));
+        if c > 0 {
+            push(c, steps);
+            steps.push((OpCode::ADD, 1));
+        }
+    }
+}
+
+/// Parses a program into its lanes, which start at the first line, at every label and after
+/// every jump.
+fn parse(src: &str) -> io::Result<Vec<Lane<usize>>> {
+    let mut lanes: Vec<Lane<(String, usize)>> = vec![];
+    let mut labels = HashMap::new();
+    let mut steps = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(label) = line.strip_suffix(':') {
+            let label = label.trim();
+            if !is_label(label) {
+                return Err(invalid(i + 1, format!("invalid label '{}'", label)));
+            }
+            // The lane so far falls through to the one the label starts
+            if !steps.is_empty() {
+                let end = End::Jump((label.to_string(), i + 1));
+                lanes.push(Lane { steps: mem::take(&mut steps), end });
+            }
+            if labels.insert(label.to_string(), lanes.len()).is_some() {
+                return Err(invalid(i + 1, format!("label '{}' is already defined", label)));
+            }
+            continue;
+        }
+
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(at) => (&line[..at], line[at..].trim()),
+            None => (line, ""),
+        };
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "jmp" | "jz" | "jnz" => {
+                if !is_label(arg) {
+                    return Err(invalid(i + 1, format!("{} needs a label to jump to", name)));
+                }
+                let target = (arg.to_string(), i + 1);
+                let end = match name.as_str() {
+                    "jmp" => End::Jump(target),
+                    "jz" => {
+                        steps.push((OpCode::NOT, 1));
+                        End::Branch(target)
+                    }
+                    _ => {
+                        steps.push((OpCode::NOT, 1));
+                        steps.push((OpCode::NOT, 1));
+                        End::Branch(target)
+                    }
+                };
+                lanes.push(Lane { steps: mem::take(&mut steps), end });
+                continue;
+            }
+            "halt" => {
+                if !arg.is_empty() {
+                    return Err(invalid(i + 1, "halt doesn't take an argument".to_string()));
+                }
+                lanes.push(Lane { steps: mem::take(&mut steps), end: End::Halt });
+                continue;
+            }
+            _ => {}
+        }
+
+        let op = parse_op(&name)
+            .ok_or_else(|| invalid(i + 1, format!("unknown command '{}'", name)))?;
+        match op {
+            OpCode::PUSH => match parse_value(arg) {
+                Some(n) if i32::try_from(n).is_ok() => push(n, &mut steps),
+                Some(_) => {
+                    return Err(invalid(
+                        i + 1,
+                        format!("can't push '{}', which doesn't fit in 32 bits", arg),
+                    ));
+                }
+                None => return Err(invalid(i + 1, format!("can't push '{}'", arg))),
+            },
+            OpCode::PTR | OpCode::SWTCH => {
+                return Err(invalid(
+                    i + 1,
+                    format!("{} would steer the PC off its path, use jz or jnz to branch", name),
+                ));
+            }
+            _ if !arg.is_empty() => {
+                return Err(invalid(i + 1, format!("{} doesn't take an argument", name)));
+            }
+            _ => steps.push((op, 1)),
+        }
+    }
+
+    // Whatever follows the last jump halts, if there's any way to get there
+    let reachable = lanes.last().is_none_or(|lane| matches!(lane.end, End::Branch(_)))
+        || labels.values().any(|&j| j == lanes.len());
+    if !steps.is_empty() || reachable {
+        lanes.push(Lane { steps, end: End::Halt });
+    }
+
+    let resolve = |(label, line): (String, usize)| {
+        labels
+            .get(&label)
+            .copied()
+            .ok_or_else(|| invalid(line, format!("undefined label '{}'", label)))
+    };
+    lanes
+        .into_iter()
+        .map(|lane| {
+            let end = match lane.end {
+                End::Halt => End::Halt,
+                End::Jump(target) => End::Jump(resolve(target)?),
+                End::Branch(target) => End::Branch(resolve(target)?),
+            };
+            let mut steps = lane.steps;
+            // The block the PC halts in can't be the one it enters the lane at, which it could
+            // leave the way it came
+            if steps.is_empty() {
+                if let End::Halt = end {
+                    steps = vec![(OpCode::PUSH, 1), (OpCode::POP, 1)];
+                }
+            }
+            Ok(Lane { steps, end })
+        })
+        .collect()
+}
+
+/// The color of the block that follows a block of color `t` when executing `op`.
+fn next_color(t: Type, op: OpCode) -> Type {
+    let (dh, dl) = op.color_delta();
+    match t {
+        Type::Color(l, h) => Type::Color(
+            LIGHTNESSES[(l as usize + dl as usize) % 3],
+            HUES[(h as usize + dh as usize) % 6],
+        ),
+        _ => unreachable!("only colored blocks execute commands"),
+    }
+}
+
+/// How many columns a block of `size` codels takes at the given height, counting the column
+/// with just its top codel.
+fn block_width(size: usize, height: usize) -> usize {
+    1 + (size - 1).div_ceil(height)
+}
+
+fn fill(codels: &mut [Vec<Type>], xs: Range<usize>, ys: Range<usize>, t: Type) {
+    for row in &mut codels[ys] {
+        for codel in &mut row[xs.clone()] {
+            *codel = t;
+        }
+    }
+}
+
+/// Assembles a program into rows of codels, which can be rendered with `blocks::render`.
+///
+/// Every lane is a band of rows, and the PC enters it at its top left moving right. A block is
+/// painted column by column, from the top down, at a fixed height, and then ends in a column of
+/// just its top codel, which is where the PC leaves it whatever the CC. A lane that halts ends
+/// in a block that runs down its right edge and back along its bottom, under a row of black,
+/// which leaves the PC no way out.
+///
+/// Jumps are routed through white, where the PC only ever turns clockwise: right along the
+/// lane's top row, down a column of its own on the right, left along a row of its own below all
+/// the lanes, and up a column of its own on the left, to the top row of the lane it jumps to.
+/// Wherever these cross the PC slides straight over. A branch is a block out on the right, left
+/// through a PTR, so that the PC turns down on a 1 and takes the jump, or carries on right on a
+/// 0 and falls through to the next lane.
+pub fn assemble(src: &str) -> io::Result<Vec<Vec<Type>>> {
+    let lanes = parse(src)?;
+
+    let height = lanes
+        .iter()
+        .flat_map(|lane| &lane.steps)
+        .map(|&(_, size)| (1..).find(|h| h * h >= size - 1).unwrap())
+        .max()
+        .unwrap_or(1);
+    let band = height + 3;
+
+    // The lanes that get jumped to, and the column on the left that leads up to each
+    let mut targets: Vec<usize> = lanes
+        .iter()
+        .enumerate()
+        .flat_map(|(k, lane)| match lane.end {
+            End::Halt => vec![],
+            End::Jump(j) => vec![j],
+            End::Branch(j) => vec![j, k + 1],
+        })
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let channel = |j| 2 * targets.binary_search(&j).unwrap() + 1;
+    let left = if targets.is_empty() { 0 } else { 2 * targets.len() + 1 };
+
+    let widths: Vec<usize> = lanes
+        .iter()
+        .map(|lane| {
+            lane.steps.iter().map(|&(_, size)| block_width(size, height)).sum::<usize>() + 1
+        })
+        .collect();
+    // Branches go in their own columns past the longest lane, a step further right per lane
+    let branches = left + widths.iter().max().unwrap() + 1;
+
+    // Every jump as the column it goes down, the lane it leaves, the lane it goes to, and the
+    // column its run along the lane's top row starts at. A branch that's taken goes straight
+    // down from the branch.
+    let mut routes = vec![];
+    let mut column = branches + 2 * lanes.len() + 1;
+    for (k, lane) in lanes.iter().enumerate() {
+        match lane.end {
+            End::Halt => {}
+            End::Jump(j) => {
+                routes.push((column, k, j, Some(left + widths[k])));
+                column += 2;
+            }
+            End::Branch(j) => {
+                let x = branches + 2 * k + 1;
+                routes.push((x, k, j, None));
+                routes.push((column, k, k + 1, Some(x + 1)));
+                column += 2;
+            }
+        }
+    }
+    // The further right a jump goes down, the higher its row, so the PC turns left at the end
+    // of every column rather than crossing the rows below
+    routes.sort_unstable_by_key(|route| Reverse(route.0));
+
+    let top = lanes.len() * band;
+    let width = routes
+        .iter()
+        .map(|route| route.0 + 1)
+        .chain(widths.iter().map(|w| left + w))
+        .max()
+        .unwrap();
+    let rows = if routes.is_empty() { top - 1 } else { top + routes.len() };
+    let mut codels = vec![vec![Type::Black; width]; rows];
+
+    for (k, lane) in lanes.iter().enumerate() {
+        let y = k * band;
+        if k == 0 {
+            fill(&mut codels, 0..left, y..y + 1, Type::White);
+        } else if targets.binary_search(&k).is_ok() {
+            fill(&mut codels, channel(k)..left, y..y + 1, Type::White);
+        }
+
+        let mut color = START;
+        let mut x = left;
+        for &(op, size) in &lane.steps {
+            for i in 0..size - 1 {
+                codels[y + i % height][x + i / height] = color;
+            }
+            x += block_width(size, height) - 1;
+            codels[y][x] = color;
+            x += 1;
+            color = next_color(color, op);
+        }
+
+        match lane.end {
+            End::Halt => {
+                fill(&mut codels, x..x + 1, y..y + height + 2, color);
+                fill(&mut codels, left..x + 1, y + height + 1..y + height + 2, color);
+            }
+            End::Jump(_) => codels[y][x] = color,
+            End::Branch(_) => {
+                codels[y][x] = color;
+                let branch = branches + 2 * k;
+                fill(&mut codels, x + 1..branch, y..y + 1, Type::White);
+                codels[y][branch] = START;
+                codels[y][branch + 1] = next_color(START, OpCode::PTR);
+            }
+        }
+    }
+
+    for (i, &(x, from, to, run)) in routes.iter().enumerate() {
+        let (y, row) = (from * band, top + i);
+        if let Some(start) = run {
+            fill(&mut codels, start..x + 1, y..y + 1, Type::White);
+        }
+        fill(&mut codels, x..x + 1, y + 1..row + 1, Type::White);
+        fill(&mut codels, channel(to)..x + 1, row..row + 1, Type::White);
+        fill(&mut codels, channel(to)..channel(to) + 1, to * band..row, Type::White);
+    }
+    Ok(codels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{render, Blocks, UnknownColor};
+    use crate::cpu::{Config, CPU};
+    use crate::iobackend::BufferIo;
+    use crate::number::Overflow;
+
+    /// Assembles a program and runs it on `input` in strict mode, trapping on overflow, returning
+    /// what it printed.
+    fn run(src: &str, input: &str) -> String {
+        let codels = assemble(src).unwrap();
+        let code = Blocks::from_image(&render(&codels, 1), Some(1), UnknownColor::Error).unwrap();
+        let cfg = Config {
+            strict: true,
+            overflow: Overflow::Trap,
+            max_steps: Some(100_000),
+            ..Config::default()
+        };
+        let mut cpu = CPU::new(code, &cfg, BufferIo::new(input.as_bytes().to_vec()));
+        cpu.run(|_| {}).unwrap();
+        String::from_utf8(cpu.io_mut().take_output()).unwrap()
+    }
+
+    fn error(src: &str) -> String {
+        assemble(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn straight_line() {
+        assert_eq!(run("push 'H'\noutc\npush 'i'\noutc\n", ""), "Hi");
+        assert_eq!(run("inpn\ninpn\nadd\noutn\n", "2 40"), "42");
+    }
+
+    #[test]
+    fn pushes_large_and_negative_values() {
+        let src = "push 0\noutn\npush 1000\noutn\npush -1000\noutn\npush 65\npush -65\nadd\noutn\n";
+        assert_eq!(run(src, ""), "01000-10000");
+    }
+
+    #[test]
+    fn pushes_values_up_to_32_bits() {
+        let src = "push 2147483647\noutn\npush -2147483648\noutn\n";
+        assert_eq!(run(src, ""), "2147483647-2147483648");
+        assert_eq!(
+            error("push 2147483648\n"),
+            "line 1: can't push '2147483648', which doesn't fit in 32 bits"
+        );
+        assert_eq!(
+            error("push -2147483649\n"),
+            "line 1: can't push '-2147483649', which doesn't fit in 32 bits"
+        );
+    }
+
+    #[test]
+    fn loops_backwards() {
+        let src = "
+            # Counts down from 5
+                push 5
+            loop:
+                dup
+                outn
+                push 1
+                sub
+                dup
+                jnz loop
+                pop
+        ";
+        assert_eq!(run(src, ""), "54321");
+    }
+
+    #[test]
+    fn jumps_forwards() {
+        let src = "
+                push 1
+                outn
+                jmp end
+                push 2
+                outn
+            end:
+                push 3
+                outn
+        ";
+        assert_eq!(run(src, ""), "13");
+    }
+
+    #[test]
+    fn branches_on_zero() {
+        let src = "
+                inpn
+                jz zero
+                push 'n'
+                outc
+                halt
+            zero:
+                push 'z'
+                outc
+        ";
+        assert_eq!(run(src, "0"), "z");
+        assert_eq!(run(src, "7"), "n");
+    }
+
+    #[test]
+    fn nests_loops() {
+        // Prints a triangle of stars, one more on every line
+        let src = "
+                push 1
+            line:
+                dup
+            star:
+                push '*'
+                outc
+                push 1
+                sub
+                dup
+                jnz star
+                pop
+                push 10
+                outc
+                push 1
+                add
+                dup
+                push 4
+                gt
+                jz line
+                pop
+        ";
+        assert_eq!(run(src, ""), "*\n**\n***\n****\n");
+    }
+
+    #[test]
+    fn rejects_bad_programs() {
+        assert_eq!(error("jmp nowhere\n"), "line 1: undefined label 'nowhere'");
+        assert_eq!(error("a:\na:\n"), "line 2: label 'a' is already defined");
+        assert_eq!(error("jz\n"), "line 1: jz needs a label to jump to");
+        assert_eq!(error("halt 1\n"), "line 1: halt doesn't take an argument");
+        assert_eq!(error("frob\n"), "line 1: unknown command 'frob'");
+        assert_eq!(error("nop\n"), "line 1: unknown command 'nop'");
+        assert_eq!(error("push x\n"), "line 1: can't push 'x'");
+        assert!(error("ptr\n").contains("use jz or jnz to branch"));
+    }
+}
@@ -1,6 +1,12 @@
 use image::io::Reader;
-use std::collections::{HashSet, HashMap};
+use image::{DynamicImage, ImageError, RgbImage};
+use std::collections::HashSet;
+use std::fmt;
 use std::io;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::str::FromStr;
+use crate::cpu::{rotate_direction, switch_codel, Direction};
+use crate::text;
 use crate::utils::Coord;
 
 #[derive(Debug, PartialEq, Clone, Copy, Eq)]
@@ -22,61 +28,179 @@ pub enum Type {
     White
 }
 
-pub fn to_blocktype(color: &[u8; 3]) -> Type {
-    match color {
-        [0xff, 0xc0, 0xc0] => Type::Color(Lightness::Light, Hue::Red),
-        [0xff, 0x00, 0x00] => Type::Color(Lightness::Normal, Hue::Red),
-        [0xc0, 0x00, 0x00] => Type::Color(Lightness::Dark, Hue::Red),
-        [0xff, 0xff, 0xc0] => Type::Color(Lightness::Light, Hue::Yellow),
-        [0xff, 0xff, 0x00] => Type::Color(Lightness::Normal, Hue::Yellow),
-        [0xc0, 0xc0, 0x00] => Type::Color(Lightness::Dark, Hue::Yellow),
-        [0xc0, 0xff, 0xc0] => Type::Color(Lightness::Light, Hue::Green),
-        [0x00, 0xff, 0x00] => Type::Color(Lightness::Normal, Hue::Green),
-        [0x00, 0xc0, 0x00] => Type::Color(Lightness::Dark, Hue::Green),
-        [0xc0, 0xff, 0xff] => Type::Color(Lightness::Light, Hue::Cyan),
-        [0x00, 0xff, 0xff] => Type::Color(Lightness::Normal, Hue::Cyan),
-        [0x00, 0xc0, 0xc0] => Type::Color(Lightness::Dark, Hue::Cyan),
-        [0xc0, 0xc0, 0xff] => Type::Color(Lightness::Light, Hue::Blue),
-        [0x00, 0x00, 0xff] => Type::Color(Lightness::Normal, Hue::Blue),
-        [0x00, 0x00, 0xc0] => Type::Color(Lightness::Dark, Hue::Blue),
-        [0xff, 0xc0, 0xff] => Type::Color(Lightness::Light, Hue::Magenta),
-        [0xff, 0x00, 0xff] => Type::Color(Lightness::Normal, Hue::Magenta),
-        [0xc0, 0x00, 0xc0] => Type::Color(Lightness::Dark, Hue::Magenta),
-
-        [0x00, 0x00, 0x00] => Type::Black,
-        [0xff, 0xff, 0xff] => Type::White,
-        _ => panic!("Invalid color type #{:?}", color)
-    }
-}
-
-#[derive(Debug)]
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Color(l, h) => write!(f, "{:?} {:?}", l, h),
+            Type::Black => write!(f, "Black"),
+            Type::White => write!(f, "White"),
+        }
+    }
+}
+
+const PALETTE: [([u8; 3], Type); 20] = [
+    ([0xff, 0xc0, 0xc0], Type::Color(Lightness::Light, Hue::Red)),
+    ([0xff, 0x00, 0x00], Type::Color(Lightness::Normal, Hue::Red)),
+    ([0xc0, 0x00, 0x00], Type::Color(Lightness::Dark, Hue::Red)),
+    ([0xff, 0xff, 0xc0], Type::Color(Lightness::Light, Hue::Yellow)),
+    ([0xff, 0xff, 0x00], Type::Color(Lightness::Normal, Hue::Yellow)),
+    ([0xc0, 0xc0, 0x00], Type::Color(Lightness::Dark, Hue::Yellow)),
+    ([0xc0, 0xff, 0xc0], Type::Color(Lightness::Light, Hue::Green)),
+    ([0x00, 0xff, 0x00], Type::Color(Lightness::Normal, Hue::Green)),
+    ([0x00, 0xc0, 0x00], Type::Color(Lightness::Dark, Hue::Green)),
+    ([0xc0, 0xff, 0xff], Type::Color(Lightness::Light, Hue::Cyan)),
+    ([0x00, 0xff, 0xff], Type::Color(Lightness::Normal, Hue::Cyan)),
+    ([0x00, 0xc0, 0xc0], Type::Color(Lightness::Dark, Hue::Cyan)),
+    ([0xc0, 0xc0, 0xff], Type::Color(Lightness::Light, Hue::Blue)),
+    ([0x00, 0x00, 0xff], Type::Color(Lightness::Normal, Hue::Blue)),
+    ([0x00, 0x00, 0xc0], Type::Color(Lightness::Dark, Hue::Blue)),
+    ([0xff, 0xc0, 0xff], Type::Color(Lightness::Light, Hue::Magenta)),
+    ([0xff, 0x00, 0xff], Type::Color(Lightness::Normal, Hue::Magenta)),
+    ([0xc0, 0x00, 0xc0], Type::Color(Lightness::Dark, Hue::Magenta)),
+    ([0x00, 0x00, 0x00], Type::Black),
+    ([0xff, 0xff, 0xff], Type::White),
+];
+
+/// Converts a pixel to its block type, or `None` if it is not one of the 20 Piet colors.
+pub fn to_blocktype(color: &[u8; 3]) -> Option<Type> {
+    PALETTE.iter().find(|(c, _)| c == color).map(|(_, t)| *t)
+}
+
+/// Converts a block type back to its pixel color.
+pub fn to_color(t: Type) -> [u8; 3] {
+    PALETTE.iter().find(|(_, pt)| *pt == t).map(|(c, _)| *c).unwrap()
+}
+
+/// A dim gray with the brightness of `color`, for codels marked as never run.
+pub fn dimmed(color: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = color;
+    [((u32::from(r) + u32::from(g) + u32::from(b)) / 6) as u8; 3]
+}
+
+/// Paints rows of codels into an image, with every codel `codel_size` pixels wide and high.
+pub fn render(codels: &[Vec<Type>], codel_size: u32) -> RgbImage {
+    let height = codels.len() as u32;
+    let width = codels.first().map_or(0, |row| row.len()) as u32;
+    RgbImage::from_fn(width * codel_size, height * codel_size, |x, y| {
+        let t = codels[(y / codel_size) as usize][(x / codel_size) as usize];
+        image::Rgb(to_color(t))
+    })
+}
+
+/// Finds the Piet color closest to `color`, as long as the euclidean distance between the two is
+/// at most `tolerance`.
+pub fn nearest_blocktype(color: &[u8; 3], tolerance: u32) -> Option<Type> {
+    let distance = |c: &[u8; 3]| -> u32 {
+        c.iter()
+            .zip(color.iter())
+            .map(|(&a, &b)| (i32::from(a) - i32::from(b)).pow(2) as u32)
+            .sum()
+    };
+    let (c, t) = PALETTE.iter().min_by_key(|(c, _)| distance(c))?;
+    if f64::from(distance(c)).sqrt() <= f64::from(tolerance) {
+        Some(*t)
+    } else {
+        None
+    }
+}
+
+/// What to do with pixels that are not one of the 20 Piet colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownColor {
+    White,
+    Black,
+    /// Snap to the closest Piet color within the given distance, failing if there is none
+    Nearest(u32),
+    Error,
+}
+
+impl UnknownColor {
+    /// Resolves a pixel into a block type under this policy. Returns `None` if the pixel cannot
+    /// be resolved, in which case loading should fail.
+    pub fn resolve(self, color: &[u8; 3]) -> Option<Type> {
+        to_blocktype(color).or(match self {
+            UnknownColor::White => Some(Type::White),
+            UnknownColor::Black => Some(Type::Black),
+            UnknownColor::Nearest(tolerance) => nearest_blocktype(color, tolerance),
+            UnknownColor::Error => None,
+        })
+    }
+}
+
+impl FromStr for UnknownColor {
+    type Err = String;
+
+    /// Parses one of `white`, `black`, `error`, `nearest` or `nearest:<tolerance>`.
+    fn from_str(s: &str) -> Result<UnknownColor, String> {
+        match s {
+            "white" => Ok(UnknownColor::White),
+            "black" => Ok(UnknownColor::Black),
+            "error" => Ok(UnknownColor::Error),
+            "nearest" => Ok(UnknownColor::Nearest(u32::MAX)),
+            _ if s.starts_with("nearest:") => s["nearest:".len()..]
+                .parse()
+                .map(UnknownColor::Nearest)
+                .map_err(|_| format!("Invalid tolerance in '{}'", s)),
+            _ => Err(format!("Unknown color policy '{}'", s)),
+        }
+    }
+}
+
+/// Guesses the codel size of an image, in pixels. Every run of same-colored pixels along a row or
+/// a column spans a whole number of codels, so the codel size is the GCD of all run lengths.
+pub fn detect_codel_size(img: &RgbImage) -> i32 {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+
+    let (w, h) = img.dimensions();
+    let mut size = 0;
+
+    for y in 0..h {
+        let mut run = 1;
+        for x in 1..w {
+            if img.get_pixel(x, y) == img.get_pixel(x - 1, y) {
+                run += 1;
+            } else {
+                size = gcd(size, run);
+                run = 1;
+            }
+        }
+        size = gcd(size, run);
+    }
+    for x in 0..w {
+        let mut run = 1;
+        for y in 1..h {
+            if img.get_pixel(x, y) == img.get_pixel(x, y - 1) {
+                run += 1;
+            } else {
+                size = gcd(size, run);
+                run = 1;
+            }
+        }
+        size = gcd(size, run);
+    }
+
+    if size == 0 { 1 } else { size as i32 }
+}
+
+#[derive(Debug, Clone)]
 pub struct Block {
     pub t: Type,
-    pub coords: HashSet<Coord>
+    pub coords: Vec<Coord>
 }
 
 /// A viewable block is meant for when you want to **not** incur the cost of copying an actual
 /// block, but still want to get an immutable borrow (why would you want a mutable one??), and
 /// don't want to mess up the whole mutable/immutable borrow thing down the line. Does not have any
 /// of the coordinates of an actual block.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ViewableBlock {
     pub t: Type,
     pub num: usize
 }
 
 impl Block {
-    pub fn is_next_to(&self, other: Coord) -> bool {
-        let (x, y) = other;
-        let left = &(x - 1, y);
-        let right = &(x + 1, y);
-        let up = &(x, y - 1);
-        let down = &(x, y + 1);
-
-        self.coords.contains(left) || self.coords.contains(right) ||
-            self.coords.contains(up) || self.coords.contains(down)
-    }
-
     pub fn to_viewableblock(&self) -> ViewableBlock {
         ViewableBlock {
             t: self.t,
@@ -85,9 +209,61 @@ impl Block {
     }
 }
 
+/// Where the PC goes when it leaves a block with a given DP and CC.
+#[derive(Debug, Clone, Copy)]
+pub struct Exit {
+    /// The codel of the block that the PC leaves from
+    pub from: Coord,
+    /// The codel the PC moves into, which may be outside of the image
+    pub to: Coord,
+    /// The index of the block containing `to`, if it is inside the image
+    pub block: Option<usize>,
+}
+
+fn unit_vector(d: Direction) -> Coord {
+    match d {
+        Direction::Right => (1, 0),
+        Direction::Down => (0, 1),
+        Direction::Left => (-1, 0),
+        Direction::Up => (0, -1),
+    }
+}
+
+fn exit_index(dp: Direction, cc: Direction) -> usize {
+    dp as usize * 2 + if cc == Direction::Left { 0 } else { 1 }
+}
+
+/// Decodes an image, reporting failures as I/O errors like the rest of loading does.
+fn decode<R: BufRead + Seek>(reader: Reader<R>) -> Result<RgbImage, io::Error> {
+    match reader.decode() {
+        Ok(img) => Ok(img.to_rgb()),
+        Err(ImageError::IoError(e)) => Err(e),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+/// Where sliding through white codels ends up, along with the DP and CC at that point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slide {
+    /// Reached the given colored codel
+    Reached(Coord, Direction, Direction),
+    /// Went round in circles, stopping on the given white codel
+    Trapped(Coord, Direction, Direction),
+}
+
+/// All the blocks of a program. Codels are stored in a dense row-major grid, where each codel
+/// holds the index of the block it belongs to.
+#[derive(Clone)]
 pub struct Blocks {
     blocks: Vec<Block>,
-    blk_lookup: HashMap<Coord, usize>
+    /// The exits of every block, for all 8 combinations of DP and CC
+    exits: Vec<[Exit; 8]>,
+    grid: Vec<usize>,
+    width: usize,
+    height: usize,
+    codel_size: i32,
+    /// Whether the codel size was guessed from the image
+    codel_size_detected: bool,
 }
 
 impl <'a> Blocks {
@@ -95,65 +271,230 @@ impl <'a> Blocks {
         self.blocks.len()
     }
 
-    pub fn from_file(filename: &str, codel_size: i32) -> Result<Blocks, io::Error> {
-        let img = Reader::open(filename)?.decode().unwrap();
-        let mut blocks: Vec<Block> = vec![];
-        let mut lookup: HashMap<Coord, usize> = HashMap::new();
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn codel_size(&self) -> i32 {
+        self.codel_size
+    }
+
+    pub fn codel_size_detected(&self) -> bool {
+        self.codel_size_detected
+    }
+
+    /// Loads the blocks from an image file. See `from_image` for the other arguments.
+    pub fn from_file(filename: &str, codel_size: Option<i32>, unknown: UnknownColor)
+        -> Result<Blocks, io::Error> {
+        Reader::open(filename)
+            .and_then(|reader| decode(reader.with_guessed_format()?))
+            .and_then(|img| Blocks::from_image(&img, codel_size, unknown))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", filename, e)))
+    }
 
-        let img = img.to_rgb();
+    /// Loads the blocks from an encoded image, in any format the `image` crate can guess.
+    pub fn from_bytes(bytes: &[u8], codel_size: Option<i32>, unknown: UnknownColor)
+        -> Result<Blocks, io::Error> {
+        Blocks::from_reader(Cursor::new(bytes), codel_size, unknown)
+    }
+
+    /// Loads the blocks from an encoded image read from `reader`, in any format the `image` crate
+    /// can guess.
+    pub fn from_reader<R: Read + Seek>(reader: R, codel_size: Option<i32>, unknown: UnknownColor)
+        -> Result<Blocks, io::Error> {
+        let img = decode(Reader::new(BufReader::new(reader)).with_guessed_format()?)?;
+        Blocks::from_image(&img, codel_size, unknown)
+    }
+
+    /// Loads the blocks from a program in the text format of the `text` module.
+    pub fn from_text(src: &str) -> Result<Blocks, io::Error> {
+        let codels = text::parse(src)?;
+        Blocks::from_image(&render(&codels, 1), Some(1), UnknownColor::Error)
+    }
+
+    /// Loads the blocks from an image that has already been decoded.
+    pub fn from_dynamic_image(img: &DynamicImage, codel_size: Option<i32>, unknown: UnknownColor)
+        -> Result<Blocks, io::Error> {
+        Blocks::from_image(&img.to_rgb(), codel_size, unknown)
+    }
+
+    /// Loads the blocks from an image. If `codel_size` is `None`, the codel size is guessed from
+    /// the image with `detect_codel_size`. Pixels that are not Piet colors are handled according
+    /// to `unknown`.
+    pub fn from_image(img: &RgbImage, codel_size: Option<i32>, unknown: UnknownColor)
+        -> Result<Blocks, io::Error> {
         let (w, h) = img.dimensions();
+        if w == 0 || h == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the image is empty"));
+        }
+        let codel_size_detected = codel_size.is_none();
+        let codel_size = codel_size.unwrap_or_else(|| detect_codel_size(img));
+        if codel_size <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid codel size {}", codel_size),
+            ));
+        }
+        let step = codel_size as usize;
+        let (width, height) = ((w as usize).div_ceil(step), (h as usize).div_ceil(step));
 
-        // Add coordinates to the blocks
-        for x in (0..w).step_by(codel_size as usize) {
-            for y in (0..h).step_by(codel_size as usize) {
+        // Sample the top left pixel of every codel
+        let mut types: Vec<Type> = Vec::with_capacity(width * height);
+        for y in (0..h).step_by(step) {
+            for x in (0..w).step_by(step) {
                 let p = img.get_pixel(x, y);
-                let t = to_blocktype(&[p[0], p[1], p[2]]);
-                let coord: Coord = (x as i32, y as i32);
-                let mut found = false;
-                let mut found_block = 0;
-                let mut i = 0;
-                while i < blocks.len() {
-                    if !found && blocks[i].t == t && blocks[i].is_next_to(coord) {
-                        blocks[i].coords.insert(coord);
-                        found = true;
-                        found_block = i;
-                    } else if found && blocks[i].t == t && blocks[i].is_next_to(coord) {
-                        // We can join these two blocks
-                        // Join them without using the join function, because Rust likes to
-                        // invalidate and delete references that were removed and deleted
-                        for crd in blocks[i].coords.clone().iter() {
-                            let crd = crd.clone();
-                            blocks[found_block].coords.insert(crd);
-                        }
-                        blocks.remove(i);
-                        continue;
+                types.push(match unknown.resolve(&[p[0], p[1], p[2]]) {
+                    Some(t) => t,
+                    None => return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "unknown color #{:02x}{:02x}{:02x} at pixel ({}, {})",
+                            p[0], p[1], p[2], x, y
+                        ),
+                    )),
+                });
+            }
+        }
+
+        // Flood fill every codel that is not part of a block yet
+        let mut blocks: Vec<Block> = vec![];
+        let mut grid: Vec<usize> = vec![usize::MAX; width * height];
+        let mut pending: Vec<usize> = vec![];
+        for start in 0..grid.len() {
+            if grid[start] != usize::MAX {
+                continue;
+            }
+
+            let t = types[start];
+            let mut coords: Vec<Coord> = vec![];
+            grid[start] = blocks.len();
+            pending.push(start);
+            while let Some(i) = pending.pop() {
+                let (x, y) = (i % width, i / width);
+                coords.push(((x * step) as i32, (y * step) as i32));
+
+                let neighbours = [
+                    if x > 0 { Some(i - 1) } else { None },
+                    if x + 1 < width { Some(i + 1) } else { None },
+                    if y > 0 { Some(i - width) } else { None },
+                    if y + 1 < height { Some(i + width) } else { None },
+                ];
+                for &n in neighbours.iter().flatten() {
+                    if grid[n] == usize::MAX && types[n] == t {
+                        grid[n] = blocks.len();
+                        pending.push(n);
                     }
-                    i += 1;
                 }
+            }
+            blocks.push(Block {t, coords});
+        }
 
-                if !found {
-                    let mut hs: HashSet<Coord> = HashSet::new();
-                    hs.insert(coord);
-                    blocks.push(Block {t: t, coords: hs});
-                }
+        let mut blks = Blocks {
+            blocks, exits: vec![], grid, width, height, codel_size, codel_size_detected
+        };
+        blks.exits = blks.blocks.iter().map(|b| blks.find_exits(b)).collect();
+        Ok(blks)
+    }
+
+    /// Works out the exits of a block. The exit codel is the one furthest along the DP, with ties
+    /// broken by going furthest towards the CC, which points counter-clockwise of the DP when it
+    /// is left and clockwise when it is right.
+    fn find_exits(&self, blk: &Block) -> [Exit; 8] {
+        let directions = [Direction::Right, Direction::Down, Direction::Left, Direction::Up];
+        let mut exits = [Exit {from: (0, 0), to: (0, 0), block: None}; 8];
+        for (i, &dp) in directions.iter().enumerate() {
+            for &cc in [Direction::Left, Direction::Right].iter() {
+                let turns = if cc == Direction::Left { 3 } else { 1 };
+                let (dx, dy) = unit_vector(dp);
+                let (cx, cy) = unit_vector(directions[(i + turns) % 4]);
+                let (x, y) = *blk.coords
+                    .iter()
+                    .max_by_key(|(x, y)| (x * dx + y * dy, x * cx + y * cy))
+                    .unwrap();
+                let to = self.next_coord((x, y), dp);
+                exits[exit_index(dp, cc)] = Exit {from: (x, y), to, block: self.find_index(&to)};
             }
         }
+        exits
+    }
+
+    pub fn get(&self, i: usize) -> &Block {
+        &self.blocks[i]
+    }
+
+    /// Looks up where the PC goes when leaving block `i` with the given DP and CC.
+    pub fn exit(&self, i: usize, dp: Direction, cc: Direction) -> &Exit {
+        &self.exits[i][exit_index(dp, cc)]
+    }
+
+    /// The codel next to `(x, y)` in the direction of `dp`.
+    pub fn next_coord(&self, (x, y): Coord, dp: Direction) -> Coord {
+        let (dx, dy) = unit_vector(dp);
+        (x + dx * self.codel_size, y + dy * self.codel_size)
+    }
+
+    /// Slides through white codels from `start` along the DP until a colored block is reached.
+    /// Whenever the slide is obstructed by black or by the edge of the image, the CC is toggled
+    /// and the DP rotated clockwise before trying again. The slide is trapped once it retraces its
+    /// own path, which is detected as visiting the same codel with the same DP twice.
+    pub fn slide(&self, start: Coord, mut dp: Direction, mut cc: Direction) -> Slide {
+        let mut visited: HashSet<(Coord, Direction)> = HashSet::new();
+        let mut pos = start;
 
-        // Populate the lookup table
-        for (i, b) in blocks.iter().enumerate() {
-            for crd in b.coords.iter() {
-                lookup.insert(crd.clone(), i);
+        while visited.insert((pos, dp)) {
+            let next = self.next_coord(pos, dp);
+            match self.find_block_from_index(&next).map(|b| b.t) {
+                Some(Type::White) => pos = next,
+                Some(Type::Color(_, _)) => return Slide::Reached(next, dp, cc),
+                Some(Type::Black) | None => {
+                    cc = switch_codel(cc, 1);
+                    dp = rotate_direction(dp, 1);
+                }
             }
         }
 
-        Ok(Blocks {blocks: blocks, blk_lookup: lookup})
+        Slide::Trapped(pos, dp, cc)
+    }
+
+    /// Finds the index of the block containing the pixel `crd`, if it is inside the image.
+    pub fn find_index(&self, crd: &Coord) -> Option<usize> {
+        let (x, y) = *crd;
+        if x < 0 || y < 0 {
+            return None;
+        }
+        let (x, y) = ((x / self.codel_size) as usize, (y / self.codel_size) as usize);
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(self.grid[y * self.width + x])
     }
 
     pub fn find_block_from_index(&'a self, crd: &Coord) -> Option<&'a Block> {
-        self.blocks.get(*self.blk_lookup.get(crd)?)
+        self.blocks.get(self.find_index(crd)?)
+    }
+
+    /// Describes a block by its index, color, size and the codel it starts at.
+    pub fn describe(&self, i: usize) -> String {
+        let blk = &self.blocks[i];
+        format!(
+            "block {} ({}, {} {} at {:?})",
+            i,
+            blk.t,
+            blk.coords.len(),
+            if blk.coords.len() == 1 { "codel" } else { "codels" },
+            blk.coords[0]
+        )
+    }
+
+    /// The type of every codel, row by row.
+    pub fn codels(&self) -> Vec<Vec<Type>> {
+        self.grid
+            .chunks(self.width)
+            .map(|row| row.iter().map(|&i| self.blocks[i].t).collect())
+            .collect()
     }
 
     pub fn count_codels(&self) -> usize {
-        self.blocks.iter().map(|b| b.coords.len()).sum()
+        self.grid.len()
     }
 }
@@ -1,52 +1,337 @@
 use clap::ArgMatches;
+use piet_tools::asm;
+use piet_tools::blocks;
+use piet_tools::blocks::{Blocks, Type, UnknownColor};
+use piet_tools::coverage::Coverage;
+use piet_tools::cpu::Config;
+use piet_tools::dialect::Dialect;
+use piet_tools::disasm;
+use piet_tools::error::RunError;
+use piet_tools::iobackend::{BufferIo, Eof, FileIo, IoBackend, StdIo};
+use piet_tools::number::Overflow;
+use piet_tools::text;
+use piet_tools::trace;
 use crate::interpreter::Interpreter;
 
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
 pub struct CmdConfig <'a> {
     pub src: &'a str,
-    pub size: i32,
+    /// Codel size in pixels, or `None` to detect it from the image
+    pub size: Option<i32>,
+    pub unknown_color: UnknownColor,
+    pub vm: Config,
+    /// How to log every step of a run to stderr, if at all
+    pub trace: Option<trace::Format>,
+}
+
+fn parse_size(matches: &ArgMatches) -> Option<i32> {
+    match matches.value_of("size") {
+        Some("auto") | None => None,
+        Some(size) => size.parse().ok(),
+    }
+}
+
+/// Checks that a codel size is `auto` or a positive number of pixels.
+pub fn validate_size(size: String) -> Result<(), String> {
+    match size.parse::<i32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ if size == "auto" => Ok(()),
+        _ => Err(format!("'{}' is neither 'auto' nor a positive number of pixels", size)),
+    }
+}
+
+fn parse_unknown_color(matches: &ArgMatches) -> UnknownColor {
+    match matches.value_of("unknown-color") {
+        Some(policy) => policy.parse().unwrap_or(UnknownColor::White),
+        None => UnknownColor::White,
+    }
+}
+
+/// Checks that an unknown color policy is one `UnknownColor` can parse.
+pub fn validate_unknown_color(policy: String) -> Result<(), String> {
+    UnknownColor::from_str(&policy).map(|_| ())
+}
+
+fn parse_limit<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    let limit = matches.value_of(name)?;
+    match limit.parse() {
+        Ok(limit) => Some(limit),
+        Err(_) => {
+            eprintln!("Invalid {} '{}', ignoring it", name, limit);
+            None
+        }
+    }
+}
+
+/// Checks that a limit is a whole number, so that clap rejects a typo instead of running the
+/// program without the limit.
+pub fn validate_count(limit: String) -> Result<(), String> {
+    match limit.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a whole number", limit)),
+    }
+}
+
+/// Checks that a timeout is a number of seconds, like `validate_count`.
+pub fn validate_seconds(secs: String) -> Result<(), String> {
+    match secs.parse().map(Duration::try_from_secs_f64) {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => Err(format!("'{}' is out of range for a timeout", secs)),
+        Err(_) => Err(format!("'{}' is not a number of seconds", secs)),
+    }
+}
+
+fn parse_timeout(matches: &ArgMatches) -> Option<Duration> {
+    parse_limit(matches, "timeout").and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+fn parse_vm_config(matches: &ArgMatches) -> Config {
+    Config {
+        bigint: matches.is_present("bigint"),
+        overflow: match matches.value_of("overflow") {
+            Some(policy) => policy.parse().unwrap_or(Overflow::Wrap),
+            None => Overflow::Wrap,
+        },
+        dialect: match matches.value_of("dialect") {
+            Some(dialect) => dialect.parse().unwrap_or(Dialect::Spec),
+            None => Dialect::Spec,
+        },
+        eof: match matches.value_of("eof") {
+            Some(eof) => eof.parse().unwrap_or(Eof::Nothing),
+            None => Eof::Nothing,
+        },
+        byte_io: matches.is_present("byte-io"),
+        strict: matches.is_present("strict"),
+        max_steps: parse_limit(matches, "max-steps"),
+        timeout: parse_timeout(matches),
+        max_stack: parse_limit(matches, "max-stack"),
+        coverage: false,
+    }
+}
+
+fn parse_config<'a>(matches: &'a ArgMatches) -> CmdConfig<'a> {
+    CmdConfig {
+        src: match matches.value_of("src") {
+            Some(src) => src,
+            None => panic!("How did you manage to forget the source file??")
+        },
+        size: parse_size(matches),
+        unknown_color: parse_unknown_color(matches),
+        vm: parse_vm_config(matches),
+        trace: if matches.is_present("trace-json") {
+            Some(trace::Format::Json)
+        } else if matches.is_present("trace") {
+            Some(trace::Format::Text)
+        } else {
+            None
+        },
+    }
+}
+
+/// Unwraps the result, or exits with the error if there is one.
+fn or_exit<T>(result: io::Result<T>) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn is_text(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "txt")
+}
+
+/// Loads the program named on the command line. Files ending in `.txt` are read as text, and `-`
+/// reads either an image or text from stdin.
+pub fn load_blocks(cfg: &CmdConfig) -> io::Result<Blocks> {
+    if is_text(cfg.src) {
+        return fs::read_to_string(cfg.src)
+            .and_then(|src| Blocks::from_text(&src))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cfg.src, e)));
+    } else if cfg.src != "-" {
+        return Blocks::from_file(cfg.src, cfg.size, cfg.unknown_color);
+    }
+
+    let mut bytes = vec![];
+    io::stdin().read_to_end(&mut bytes)?;
+    // Images are binary, so anything that reads as text is taken to be text
+    match std::str::from_utf8(&bytes) {
+        Ok(src) => Blocks::from_text(src),
+        Err(_) => Blocks::from_bytes(&bytes, cfg.size, cfg.unknown_color),
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("stdin: {}", e)))
+}
+
+/// Reads a text file, or stdin if `path` is `-`.
+fn read_source(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src)?;
+        Ok(src)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+/// Writes a program out as text when `dest` ends in `.txt` or is `-` for stdout, or as an image
+/// with codels `scale` pixels wide otherwise.
+fn write_program(codels: &[Vec<Type>], dest: &str, scale: u32) -> io::Result<()> {
+    if dest == "-" {
+        print!("{}", text::to_text(codels));
+        Ok(())
+    } else if is_text(dest) {
+        fs::write(dest, text::to_text(codels))
+    } else {
+        blocks::render(codels, scale)
+            .save(dest)
+            .map_err(|e| io::Error::other(format!("{}: {}", dest, e)))
+    }
+}
+
+fn parse_scale(matches: &ArgMatches) -> u32 {
+    match matches.value_of("scale") {
+        Some(scale) => match scale.parse() {
+            Ok(scale) if scale > 0 => scale,
+            _ => {
+                eprintln!("Invalid scale '{}', using 1 instead", scale);
+                1
+            }
+        },
+        None => 1,
+    }
+}
+
+fn load<I: IoBackend>(cfg: &CmdConfig, io: I) -> Interpreter<I> {
+    or_exit(Interpreter::from_config(cfg, io))
+}
+
+/// The exit code for a strict run that faulted, or for a program that ran into a limit.
+fn exit_code(e: &RunError) -> i32 {
+    match e {
+        RunError::Fault(_) => 2,
+        RunError::Limit(_) => 3,
+    }
+}
+
+/// Exits with a non-zero code if a strict run faulted or the program ran into a limit.
+fn exit_on_error(result: Result<(), RunError>) {
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+fn run_to_end<I: IoBackend>(mut interp: Interpreter<I>) {
+    exit_on_error(interp.run());
+}
+
+/// Runs the program, then prints its profile to stderr and writes its heatmap if asked to.
+fn profile_to_end<I: IoBackend>(matches: &ArgMatches, mut interp: Interpreter<I>) {
+    let (profile, result) = interp.profile();
+    let code = interp.code();
+    eprint!("{}", profile.report(code, parse_limit(matches, "top").unwrap_or(20)));
+    if let Some(dest) = matches.value_of("heatmap") {
+        let scale = match matches.value_of("scale") {
+            Some(_) => parse_scale(matches),
+            None => code.codel_size() as u32,
+        };
+        or_exit(profile.heatmap(code, scale)
+            .save(dest)
+            .map_err(|e| io::Error::other(format!("{}: {}", dest, e))));
+    }
+    exit_on_error(result);
 }
 
 pub fn handle_config(matches: ArgMatches) {
     if let Some(run) = matches.subcommand_matches("run") {
-        let cfg = CmdConfig {
-            src: match run.value_of("src") {
-                Some(src) => src,
-                None => panic!("How did you manage to forget the source file??")
-            },
-            size: match run.value_of("size") {
-                Some(size) => size.parse().unwrap_or(1),
-                None => {
-                    println!("Did not specify size, defaulting to 1");
-                    1
-                }
-            },
-        };
-        let mut interp = Interpreter::from_config(&cfg);
+        let cfg = parse_config(run);
 
         if run.is_present("debug") {
+            let input = match run.value_of("input") {
+                Some(path) => or_exit(fs::read(path)),
+                None => vec![],
+            };
+            let mut interp = load(&cfg, BufferIo::new(input));
             match interp.debug() {
                 Err(e) => panic!("{}", e),
                 _ => println!("Exiting debugger")
             }
+        } else if run.is_present("input") || run.is_present("output") {
+            let io = or_exit(FileIo::open(run.value_of("input"), run.value_of("output")));
+            run_to_end(load(&cfg, io));
         } else {
-            interp.run();
+            run_to_end(load(&cfg, StdIo));
         }
-    } else if let Some(info) = matches.subcommand_matches("info") {
-        let cfg = CmdConfig {
-            src: match info.value_of("src") {
-                Some(src) => src,
-                None => panic!("How did you manage to forget the source file??")
-            },
-            size: match info.value_of("size") {
-                Some(size) => size.parse().unwrap_or(1),
-                None => {
-                    println!("Did not specify size, defaulting to 1");
-                    1
-                }
-            },
+    } else if let Some(prof) = matches.subcommand_matches("profile") {
+        let cfg = parse_config(prof);
+        if prof.is_present("input") || prof.is_present("output") {
+            let io = or_exit(FileIo::open(prof.value_of("input"), prof.value_of("output")));
+            profile_to_end(prof, load(&cfg, io));
+        } else {
+            profile_to_end(prof, load(&cfg, StdIo));
+        }
+    } else if let Some(cov) = matches.subcommand_matches("coverage") {
+        let mut cfg = parse_config(cov);
+        cfg.vm.coverage = true;
+        let code = or_exit(load_blocks(&cfg));
+        let inputs: Vec<Option<&str>> = match cov.values_of("input") {
+            Some(paths) => paths.map(Some).collect(),
+            None => vec![None],
         };
-        let interp = Interpreter::from_config(&cfg);
+
+        let mut coverage = Coverage::new(&code);
+        // Every run gets to finish, but the first one that fails sets the exit code
+        let mut failed = None;
+        for input in inputs {
+            let mut interp = Interpreter::new(code.clone(), &cfg, or_exit(FileIo::open(input, None)));
+            if let Err(e) = interp.run() {
+                eprintln!("error: {}: {}", input.unwrap_or("stdin"), e);
+                failed = failed.or(Some(exit_code(&e)));
+            }
+            coverage.merge(interp.coverage().unwrap());
+        }
+
+        eprint!("{}", coverage.summary(&code));
+        if let Some(dest) = cov.value_of("image") {
+            or_exit(coverage.render(&code, parse_scale(cov))
+                .save(dest)
+                .map_err(|e| io::Error::other(format!("{}: {}", dest, e))));
+        }
+        if let Some(code) = failed {
+            process::exit(code);
+        }
+    } else if let Some(info) = matches.subcommand_matches("info") {
+        let cfg = parse_config(info);
+        let interp = load(&cfg, StdIo);
         interp.info();
+    } else if let Some(convert) = matches.subcommand_matches("convert") {
+        let cfg = parse_config(convert);
+        let blocks = or_exit(load_blocks(&cfg));
+        let dest = convert.value_of("dest").unwrap();
+        or_exit(write_program(&blocks.codels(), dest, parse_scale(convert)));
+    } else if let Some(dis) = matches.subcommand_matches("disasm") {
+        let cfg = parse_config(dis);
+        let blocks = or_exit(load_blocks(&cfg));
+        if dis.is_present("dot") {
+            print!("{}", disasm::dot(&blocks));
+        } else {
+            print!("{}", disasm::listing(&blocks));
+        }
+    } else if let Some(assemble) = matches.subcommand_matches("asm") {
+        let src = assemble.value_of("src").unwrap();
+        let name = if src == "-" { "stdin" } else { src };
+        let codels = or_exit(read_source(src).and_then(|text| asm::assemble(&text))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e))));
+        let dest = assemble.value_of("dest").unwrap();
+        or_exit(write_program(&codels, dest, parse_scale(assemble)));
     }
 }
-
@@ -0,0 +1,167 @@
+//! Coverage of a program by one or more runs: which blocks were ever entered, and which of their
+//! exits were ever taken, much like line coverage for a program written as text.
+
+use std::collections::HashSet;
+
+use image::RgbImage;
+
+use crate::blocks;
+use crate::blocks::{Blocks, Type};
+use crate::cpu::{Direction, Step};
+use crate::disasm;
+use crate::disasm::{Target, Transition};
+
+/// The color of the marks on the exits that were taken, and on the ones that never were.
+const TAKEN: [u8; 3] = [0xff, 0xff, 0xff];
+const MISSED: [u8; 3] = [0x00, 0x00, 0x00];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// Whether each block was entered, by index
+    pub blocks: Vec<bool>,
+    /// The exits that were taken, as the block left along with the DP and CC it was left by
+    pub exits: HashSet<(usize, Direction, Direction)>,
+}
+
+/// The exits that lead to another block, which are the ones that can be covered.
+fn exits(code: &Blocks) -> Vec<Transition> {
+    disasm::transitions(code)
+        .into_iter()
+        .filter(|t| match t.target {
+            Target::Block { .. } => true,
+            Target::Blocked | Target::Trapped => false,
+        })
+        .collect()
+}
+
+fn percent(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        100.0 * n as f64 / total as f64
+    }
+}
+
+impl Coverage {
+    /// The coverage of a program that has only entered the block it starts in.
+    pub fn new(code: &Blocks) -> Coverage {
+        let mut blocks = vec![false; code.len()];
+        if let Some(start) = code.find_index(&(0, 0)) {
+            blocks[start] = true;
+        }
+        Coverage { blocks, exits: HashSet::new() }
+    }
+
+    /// Marks the block and exit of a step taken by the CPU as covered.
+    pub fn record(&mut self, step: &Step) {
+        self.blocks[step.to] = true;
+        self.exits.insert((step.from, step.dp, step.cc));
+    }
+
+    /// Adds what another run of the same program covered.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (covered, &other) in self.blocks.iter_mut().zip(&other.blocks) {
+            *covered |= other;
+        }
+        self.exits.extend(&other.exits);
+    }
+
+    fn is_taken(&self, t: &Transition) -> bool {
+        self.exits.contains(&(t.block, t.dp, t.cc))
+    }
+
+    /// How many colored blocks and exits were covered, followed by the blocks that never were,
+    /// and the exits never taken out of the blocks that were.
+    pub fn summary(&self, code: &Blocks) -> String {
+        let colored: Vec<usize> = (0..code.len())
+            .filter(|&i| matches!(code.get(i).t, Type::Color(_, _)))
+            .collect();
+        let entered = colored.iter().filter(|&&i| self.blocks[i]).count();
+        let exits = exits(code);
+        let taken = exits.iter().filter(|t| self.is_taken(t)).count();
+
+        let mut out = format!(
+            "{} of {} colored blocks entered ({:.2}%)\n{} of {} exits taken ({:.2}%)\n",
+            entered,
+            colored.len(),
+            percent(entered, colored.len()),
+            taken,
+            exits.len(),
+            percent(taken, exits.len())
+        );
+
+        if entered < colored.len() {
+            out += "\nBlocks never entered:\n";
+            for &i in colored.iter().filter(|&&i| !self.blocks[i]) {
+                out += &format!("    {}\n", code.describe(i));
+            }
+        }
+
+        let missed: Vec<&Transition> =
+            exits.iter().filter(|t| self.blocks[t.block] && !self.is_taken(t)).collect();
+        if !missed.is_empty() {
+            out += "\nExits never taken out of blocks that were entered:\n";
+            let mut last = None;
+            for t in missed {
+                if last != Some(t.block) {
+                    out += &format!("    {}\n", code.describe(t.block));
+                    last = Some(t.block);
+                }
+                if let Target::Block { block, .. } = t.target {
+                    let exit = format!("DP {:?}, CC {:?}", t.dp, t.cc);
+                    out += &format!(
+                        "        {:<22} from {:<10} -> block {}\n",
+                        exit,
+                        format!("{:?}:", t.from),
+                        block
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    /// Paints the program with every codel `codel_size` pixels wide, graying out the colored
+    /// blocks that were never entered. Every exit that leads to another block is marked along the
+    /// side of the codel it leaves from, on the half the CC picks: white if it was taken, and
+    /// black if not. Exits aren't marked when codels are less than 4 pixels wide.
+    pub fn render(&self, code: &Blocks, codel_size: u32) -> RgbImage {
+        let source = code.codel_size();
+        let mut img = blocks::render(&code.codels(), codel_size);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (cx, cy) = ((x / codel_size) as i32, (y / codel_size) as i32);
+            let i = code.find_index(&(cx * source, cy * source)).unwrap();
+            if let Type::Color(_, _) = code.get(i).t {
+                if !self.blocks[i] {
+                    pixel.0 = blocks::dimmed(pixel.0);
+                }
+            }
+        }
+        if codel_size < 4 {
+            return img;
+        }
+
+        let (size, half, thickness) = (codel_size, codel_size / 2, codel_size / 4);
+        for t in exits(code) {
+            let (x0, y0) = (
+                (t.from.0 / source) as u32 * codel_size,
+                (t.from.1 / source) as u32 * codel_size,
+            );
+            let left = t.cc == Direction::Left;
+            // The side the DP points at, and the half of it to the CC's side
+            let (xs, ys) = match t.dp {
+                Direction::Right => (size - thickness..size, if left { 0..half } else { half..size }),
+                Direction::Down => (if left { half..size } else { 0..half }, size - thickness..size),
+                Direction::Left => (0..thickness, if left { half..size } else { 0..half }),
+                Direction::Up => (if left { 0..half } else { half..size }, 0..thickness),
+            };
+            let color = if self.is_taken(&t) { TAKEN } else { MISSED };
+            for y in ys {
+                for x in xs.clone() {
+                    img.put_pixel(x0 + x, y0 + y, image::Rgb(color));
+                }
+            }
+        }
+        img
+    }
+}
@@ -1,11 +1,17 @@
 use crate::blocks;
-use crate::blocks::{Block, Blocks, Type};
-use crate::cmdconfig::CmdConfig;
+use crate::blocks::{Blocks, Slide, Type};
+use crate::coverage::Coverage;
+use crate::dialect::{Dialect, Rounding};
+use crate::error::{Fault, Limit, LimitError, RunError, RuntimeError};
+use crate::iobackend::{Eof, InputBuffer, IoBackend, StdIo};
+use crate::number;
+use crate::number::{Int, Overflow};
 use crate::utils::Coord;
 
-use std::io;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 #[repr(i32)]
 pub enum Direction {
     Right = 0,
@@ -14,17 +20,17 @@ pub enum Direction {
     Up,
 }
 
-fn rotate_direction(d: Direction, times: i32) -> Direction {
-    match ((d as i32) + times) % 4 {
+/// Rotates clockwise `times` times, or counter-clockwise if `times` is negative.
+pub fn rotate_direction(d: Direction, times: i32) -> Direction {
+    match ((d as i32) + times).rem_euclid(4) {
         0 => Direction::Right,
         1 => Direction::Down,
         2 => Direction::Left,
-        3 => Direction::Up,
-        x => panic!("HOWTF did you manage to get {}???", x),
+        _ => Direction::Up,
     }
 }
 
-fn switch_codel(c: Direction, times: i32) -> Direction {
+pub fn switch_codel(c: Direction, times: i32) -> Direction {
     let times = if times < 0 { -times } else { times };
     if times % 2 == 0 {
         c
@@ -37,7 +43,17 @@ fn switch_codel(c: Direction, times: i32) -> Direction {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The outcome of trying to move the PC out of the current block
+enum Move {
+    Moved,
+    /// Obstructed by black or the edge of the image
+    Blocked,
+    /// Trapped while sliding through white
+    Halted,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpCode {
     NOP,
     PUSH,
@@ -80,117 +96,520 @@ impl OpCode {
 
         OpCode::OPCODE_TABLE[hue_delta as usize][light_delta as usize]
     }
+
+    /// The change in hue and lightness that executes this command, the inverse of `typeof_exec`.
+    pub fn color_delta(self) -> (u8, u8) {
+        for (hue_delta, row) in OpCode::OPCODE_TABLE.iter().enumerate() {
+            if let Some(light_delta) = row.iter().position(|&op| op == self) {
+                return (hue_delta as u8, light_delta as u8);
+            }
+        }
+        unreachable!("{:?} is missing from the opcode table", self)
+    }
+}
+
+/// How the CPU executes a program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Keep values on the stack as big integers, only available with the `bigint` feature
+    pub bigint: bool,
+    pub overflow: Overflow,
+    pub dialect: Dialect,
+    pub eof: Eof,
+    /// Read and write characters as raw bytes instead of UTF-8
+    pub byte_io: bool,
+    /// Halt as soon as a command faults
+    pub strict: bool,
+    pub max_steps: Option<u64>,
+    /// Wall-clock time the program may run for
+    pub timeout: Option<Duration>,
+    /// The most values the stack may hold
+    pub max_stack: Option<usize>,
+    /// Whether to keep track of which blocks and exits the program covers
+    pub coverage: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bigint: false,
+            overflow: Overflow::Wrap,
+            dialect: Dialect::Spec,
+            eof: Eof::Nothing,
+            byte_io: false,
+            strict: false,
+            max_steps: None,
+            timeout: None,
+            max_stack: None,
+            coverage: false,
+        }
+    }
+}
+
+/// How the PC moved during a step: the block it left, with the DP and CC it left by, the block it
+/// ended up in, and the command that got executed, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub from: usize,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub to: usize,
+    pub op: Option<OpCode>,
+}
+
+/// What a step changed, so that it can be undone. See `CPU::step_recorded`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    /// The number of steps taken before this one
+    pub step: u64,
+    pub pc: Coord,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub halted: bool,
+    pub last_cmd: Option<OpCode>,
+    pub last_step: Option<Step>,
+    /// The values the step took off the top of the stack, from the bottom up
+    pub popped: Vec<Int>,
+    /// How many values the step put on the stack in their place
+    pub pushed: usize,
+    /// How many values a ROLL rotated below the ones it popped, and how far to the right
+    pub rolled: Option<(usize, usize)>,
+    /// The bytes of input the step consumed
+    pub input: Vec<u8>,
+    /// How many bytes of output the step wrote
+    pub output: usize,
 }
 
-pub struct CPU {
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<I: IoBackend = StdIo> {
     codel_size: i32,
     code: Blocks,
-    pub stack: Vec<i32>,
+    pub stack: Vec<Int>,
     pub dp: Direction,
     pub cc: Direction,
     pub pc: Coord,
+    /// Whether values are kept as big integers instead of wrapping around to 32 bits
+    bigint: bool,
+    overflow: Overflow,
+    dialect: Dialect,
+    io: I,
+    input: InputBuffer,
+    eof: Eof,
+    /// Whether characters are read and written as raw bytes instead of UTF-8
+    byte_io: bool,
+    /// How many bytes of output have been written so far
+    written: usize,
+    /// How many values the last ROLL rotated and how far, for `step_recorded`
+    rolled: Option<(usize, usize)>,
 
-    pub error: Option<String>,
-    pub output: Option<String>,
-    pub last_cmd: Option<OpCode>
+    /// The number of steps taken so far, including the current one
+    pub steps: u64,
+    /// Whether to halt as soon as a command faults, instead of skipping it
+    strict: bool,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    max_stack: Option<usize>,
+    pub halted: bool,
+    /// The limit the program was stopped for, if any
+    pub limit: Option<LimitError>,
+
+    pub error: Option<RuntimeError>,
+    pub last_cmd: Option<OpCode>,
+    /// How the PC moved during the last step, if it moved at all
+    pub last_step: Option<Step>,
+    /// What the program has covered so far, if asked to keep track
+    pub coverage: Option<Coverage>,
 }
 
-impl CPU {
-    pub fn from_config(cfg: &CmdConfig) -> CPU {
-        match Blocks::from_file(cfg.src, cfg.size) {
-            Ok(blocks) => CPU {
-                codel_size: cfg.size,
-                code: blocks,
-                stack: vec![],
-                dp: Direction::Right,
-                cc: Direction::Left,
-                pc: (0, 0),
-
-                error: None,
-                output: None,
-                last_cmd: None,
-            },
-            Err(e) => panic!(e),
+impl<I: IoBackend> CPU<I> {
+    pub fn new(code: Blocks, cfg: &Config, io: I) -> CPU<I> {
+        let coverage = if cfg.coverage { Some(Coverage::new(&code)) } else { None };
+        CPU {
+            codel_size: code.codel_size(),
+            code,
+            stack: vec![],
+            dp: Direction::Right,
+            cc: Direction::Left,
+            pc: (0, 0),
+            bigint: cfg.bigint,
+            overflow: cfg.overflow,
+            dialect: cfg.dialect,
+            io,
+            input: InputBuffer::new(),
+            eof: cfg.eof,
+            byte_io: cfg.byte_io,
+            written: 0,
+            rolled: None,
+
+            steps: 0,
+            strict: cfg.strict,
+            max_steps: cfg.max_steps,
+            timeout: cfg.timeout,
+            max_stack: cfg.max_stack,
+            halted: false,
+            limit: None,
+
+            error: None,
+            last_cmd: None,
+            last_step: None,
+            coverage,
         }
     }
 
     pub fn get_info(&self) -> String {
         format!(
             "Total number of blocks: {}
-Codel size: {}
+Codel size: {}{}
 # of codels: {}\n",
             self.code.len(),
             self.codel_size,
+            if self.code.codel_size_detected() { " (detected)" } else { "" },
             self.code.count_codels()
         )
     }
 
-    pub fn try_step(&mut self) -> bool {
-        for i in 0..8 {
-            if self.step() {
-                break;
+    pub fn code(&self) -> &Blocks {
+        &self.code
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Runs the program until it halts, handing every fault to `on_fault` as it happens. In strict
+    /// mode, the fault that halted the program is returned instead. Running into a limit also
+    /// stops the program with an error. The timeout is only checked between steps, so it can't
+    /// interrupt a command that is waiting for input.
+    pub fn run<F: FnMut(&RuntimeError)>(&mut self, on_fault: F) -> Result<(), RunError> {
+        self.run_traced(on_fault, |_| {})
+    }
+
+    /// Like `run`, but also hands the CPU to `on_step` after every step that moves the PC, with
+    /// the move in `last_step`.
+    pub fn run_traced<F, G>(&mut self, mut on_fault: F, mut on_step: G) -> Result<(), RunError>
+    where
+        F: FnMut(&RuntimeError),
+        G: FnMut(&CPU<I>),
+    {
+        let start = Instant::now();
+        let mut running = true;
+        let mut fault = None;
+        while running {
+            running = self.try_step();
+            if self.last_step.is_some() {
+                on_step(self);
             }
+            self.take_fault(&mut fault, &mut on_fault);
 
-            self.cc = switch_codel(self.cc, 1);
-            if i % 2 == 1 {
-                self.dp = rotate_direction(self.dp, 1);
+            if let Some(timeout) = self.timeout {
+                if running && start.elapsed() >= timeout {
+                    self.stop(Limit::Time(timeout));
+                    running = false;
+                }
             }
+        }
 
-            if i == 7 {
+        if let Err(e) = self.io.flush() {
+            self.fault(Fault::Output(e.to_string()));
+            self.take_fault(&mut fault, &mut on_fault);
+        }
+
+        if let Some(err) = fault {
+            return Err(RunError::Fault(err));
+        }
+        match self.limit.take() {
+            Some(err) => Err(RunError::Limit(err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the last fault in `fault` when strict, unless an earlier one is there already, or
+    /// hands it to `on_fault` otherwise.
+    fn take_fault<F: FnMut(&RuntimeError)>(
+        &mut self,
+        fault: &mut Option<RuntimeError>,
+        on_fault: &mut F,
+    ) {
+        if let Some(err) = self.error.take() {
+            if self.strict {
+                fault.get_or_insert(err);
+            } else {
+                on_fault(&err);
+            }
+        }
+    }
+
+    /// Moves on to the next block, returning false once the program has halted. In strict mode,
+    /// the program halts as soon as a command faults, and it is stopped once it runs into the
+    /// step or stack limit.
+    pub fn try_step(&mut self) -> bool {
+        self.last_step = None;
+        if self.halted {
+            return false;
+        }
+        if let Some(max) = self.max_steps {
+            if self.steps >= max {
+                self.stop(Limit::Steps(max));
                 return false;
             }
         }
-        true
-    }
-
-    fn step(&mut self) -> bool {
-        let blk = self.code.find_block_from_index(&self.pc).unwrap();
-        let edges = self.get_edges(blk);
-        let (choose_x, choose_y) = self.choose_coord(edges);
-        let new_coord = match self.dp {
-            Direction::Right => (choose_x + self.codel_size, choose_y),
-            Direction::Down => (choose_x, choose_y + self.codel_size),
-            Direction::Left => (choose_x - self.codel_size, choose_y),
-            Direction::Up => (choose_x, choose_y - self.codel_size),
+
+        self.steps += 1;
+        for i in 0..8 {
+            match self.step() {
+                Move::Moved => {
+                    self.halted = self.strict && self.error.is_some();
+                    if let Some(max) = self.max_stack {
+                        if self.stack.len() > max {
+                            self.stop(Limit::Stack(max));
+                        }
+                    }
+                    return !self.halted;
+                }
+                Move::Halted => {
+                    self.halted = true;
+                    return false;
+                }
+                Move::Blocked => {}
+            }
+
+            // Alternate between toggling the CC and rotating the DP
+            if i % 2 == 0 {
+                self.cc = switch_codel(self.cc, 1);
+            } else {
+                self.dp = rotate_direction(self.dp, 1);
+            }
+        }
+        self.halted = true;
+        false
+    }
+
+    /// Takes a step like `try_step`, also returning what it changed so that `undo` can take it
+    /// back.
+    pub fn step_recorded(&mut self) -> (bool, Delta) {
+        // Only ROLL reaches further down the stack than the top two values, and it says how far
+        let base = self.stack.len().saturating_sub(2);
+        let mut top = self.stack[base..].to_vec();
+        let output = self.written;
+        let mut delta = Delta {
+            step: self.steps,
+            pc: self.pc,
+            dp: self.dp,
+            cc: self.cc,
+            halted: self.halted,
+            last_cmd: self.last_cmd,
+            last_step: self.last_step,
+            popped: vec![],
+            pushed: 0,
+            rolled: None,
+            input: vec![],
+            output: 0,
         };
-        let new_blk = match self.code.find_block_from_index(&new_coord) {
-            Some(blk) => blk,
-            None => return false,
+
+        self.rolled = None;
+        self.input.record();
+        let running = self.try_step();
+        delta.rolled = self.rolled.take();
+        let kept = match delta.rolled {
+            // The arguments, with the rotated values below them
+            Some(_) => 0,
+            None => top.iter().zip(&self.stack[base..]).take_while(|(a, b)| a == b).count(),
         };
+        delta.popped = top.split_off(kept);
+        delta.pushed = self.stack.len() - base - kept;
+        delta.input = self.input.take_recorded();
+        delta.output = self.written - output;
+        (running, delta)
+    }
+
+    /// Undoes the last step recorded with `step_recorded`, putting back the stack and the input it
+    /// consumed, and clearing any fault or limit. Output can't be taken back, so dropping the
+    /// `output` bytes it wrote is up to the caller. Coverage is left as it is.
+    pub fn undo(&mut self, delta: &Delta) {
+        self.stack.truncate(self.stack.len() - delta.pushed);
+        if let Some((depth, rolls)) = delta.rolled {
+            let at = self.stack.len() - depth;
+            self.stack[at..].rotate_left(rolls);
+        }
+        self.stack.extend_from_slice(&delta.popped);
+        self.input.unread(&delta.input);
+        self.written -= delta.output;
 
+        self.steps = delta.step;
+        self.pc = delta.pc;
+        self.dp = delta.dp;
+        self.cc = delta.cc;
+        self.halted = delta.halted;
+        self.last_cmd = delta.last_cmd;
+        self.last_step = delta.last_step;
         self.error = None;
-        self.output = None;
-        let (vblk, vnewblk) = (blk.to_viewableblock(), new_blk.to_viewableblock());
-        let success = self.execute_blk(vblk, vnewblk);
-        if success {
-            if self.pc == new_coord {
-                return false;
+        self.limit = None;
+    }
+
+    fn step(&mut self) -> Move {
+        let i = self.code.find_index(&self.pc).unwrap();
+        let blk = self.code.get(i);
+        let (dp, cc) = (self.dp, self.cc);
+        if blk.t == Type::Black {
+            // Only possible when the program starts on a black codel, which it can never leave
+            return Move::Halted;
+        }
+        let moved = if blk.t == Type::White && self.dialect.slides_through_white() {
+            // Only possible when the program starts on a white codel
+            self.error = None;
+            self.last_cmd = None;
+            self.slide(self.pc)
+        } else {
+            let exit = *self.code.exit(i, self.dp, self.cc);
+            let new_blk = match exit.block {
+                Some(j) => self.code.get(j),
+                None => return Move::Blocked,
+            };
+
+            self.error = None;
+            let (vblk, vnewblk) = (blk.to_viewableblock(), new_blk.to_viewableblock());
+            self.execute_blk(vblk, vnewblk, exit.to)
+        };
+
+        if let Move::Moved = moved {
+            let step = Step {
+                from: i,
+                dp,
+                cc,
+                to: self.code.find_index(&self.pc).unwrap(),
+                op: self.last_cmd,
+            };
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record(&step);
             }
-            self.pc = new_coord;
+            self.last_step = Some(step);
         }
-        success
+        moved
     }
 
-    /// Executes the transition between blocks. Returns true if the block executes. The block does
-    /// not execute if and only if the next block is black.
+    /// Slides through white codels from `start`, moving the PC to the colored block it reaches.
+    /// No command is executed. The program halts if the slide is trapped. See `Blocks::slide`.
+    fn slide(&mut self, start: Coord) -> Move {
+        let (pc, dp, cc, moved) = match self.code.slide(start, self.dp, self.cc) {
+            Slide::Reached(pc, dp, cc) => (pc, dp, cc, Move::Moved),
+            Slide::Trapped(pc, dp, cc) => (pc, dp, cc, Move::Halted),
+        };
+        self.pc = pc;
+        self.dp = dp;
+        self.cc = cc;
+        moved
+    }
+
+    /// Executes the transition between blocks, moving the PC to `new_coord` in the next block.
+    /// Moving into a black block is blocked, while moving into a white block slides through it
+    /// without executing anything, or just stops in it under the legacy dialect.
     ///
     /// This is basically a helper function that deals with the exceptional cases.
-    fn execute_blk(&mut self, curr: blocks::ViewableBlock, next: blocks::ViewableBlock) -> bool {
+    fn execute_blk(
+        &mut self,
+        curr: blocks::ViewableBlock,
+        next: blocks::ViewableBlock,
+        new_coord: Coord,
+    ) -> Move {
         self.last_cmd = None;
         match next.t {
-            Type::Black => false,
+            Type::Black => Move::Blocked,
             Type::Color(l, h) => {
+                // Leaving a white block executes nothing, and `step` never leaves a black one
                 if let Type::Color(l0, h0) = curr.t {
                     self.execute(curr, OpCode::typeof_exec(l0, h0, l, h));
-                } else if let Type::Black = curr.t {
-                    panic!(
-                        "Your current block is {:?}, which is impossible",
-                        curr.t
-                    );
                 }
-                true
+                self.pc = new_coord;
+                Move::Moved
+            }
+            Type::White if self.dialect.slides_through_white() => self.slide(new_coord),
+            Type::White => {
+                self.pc = new_coord;
+                Move::Moved
+            }
+        }
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    fn write_output(&mut self, bytes: &[u8]) {
+        match self.io.write(bytes) {
+            Ok(()) => self.written += bytes.len(),
+            Err(e) => self.fault(Fault::Output(e.to_string())),
+        }
+    }
+
+    /// Records a fault along with where it happened.
+    fn fault(&mut self, fault: Fault) {
+        self.error = Some(RuntimeError {
+            fault,
+            step: self.steps,
+            op: self.last_cmd,
+            block: self.code.find_block_from_index(&self.pc).unwrap().to_viewableblock(),
+            pc: self.pc,
+            dp: self.dp,
+            cc: self.cc,
+        });
+    }
+
+    /// Halts the program for running into a limit, recording the state it was left in.
+    pub fn stop(&mut self, limit: Limit) {
+        self.halted = true;
+        self.limit = Some(LimitError {
+            limit,
+            step: self.steps,
+            block: self.code.find_block_from_index(&self.pc).unwrap().to_viewableblock(),
+            pc: self.pc,
+            dp: self.dp,
+            cc: self.cc,
+            stack: self.stack.clone(),
+        });
+    }
+
+    /// Checks that there are at least `required` values on the stack for `op`, raising a fault if
+    /// there are not. Depending on the dialect, the values that are there get popped anyway.
+    fn require(&mut self, op: OpCode, required: usize) -> bool {
+        let available = self.stack.len();
+        if available < required {
+            self.fault(Fault::StackUnderflow { op, required, available });
+            if self.dialect.underflow_pops() {
+                self.stack.clear();
+            }
+        }
+        available >= required
+    }
+
+    fn end_of_input(&mut self) {
+        match self.eof {
+            Eof::Nothing => {}
+            Eof::MinusOne => self.stack.push(number::from_i64(-1)),
+            Eof::Error => self.fault(Fault::EndOfInput),
+        }
+    }
+
+    /// Brings a value into range according to the overflow policy, unless big integers are
+    /// enabled. Returns `None` if the value overflows and the policy traps.
+    fn fit(&self, n: Int) -> Option<Int> {
+        if self.bigint {
+            Some(n)
+        } else {
+            self.overflow.apply(n)
+        }
+    }
+
+    /// Pushes the result of an arithmetic operation on `v2` and `v1`, where `None` means the
+    /// result did not even fit in the integer type. If the result overflows and the policy traps,
+    /// the operands are put back instead.
+    fn push_result(&mut self, op: OpCode, result: Option<Int>, v2: Int, v1: Int) {
+        match result.and_then(|n| self.fit(n)) {
+            Some(n) => self.stack.push(n),
+            None => {
+                self.fault(Fault::Overflow { op });
+                self.stack.push(v2);
+                self.stack.push(v1);
             }
-            Type::White => true,
         }
     }
 
@@ -199,256 +618,295 @@ Codel size: {}
         match op {
             OpCode::NOP => {}
             OpCode::PUSH => {
-                self.stack.push(curr.num as i32);
+                self.stack.push(number::from_i64(curr.num as i64));
             }
             OpCode::POP => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
                 self.stack.pop();
             }
             OpCode::ADD => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                self.stack.push(v1 + v2);
+                self.push_result(op, number::checked_add(&v2, &v1), v2, v1);
             }
             OpCode::SUB => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                self.stack.push(v2 - v1);
+                self.push_result(op, number::checked_sub(&v2, &v1), v2, v1);
             }
             OpCode::MUL => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                self.stack.push(v1 * v2);
+                self.push_result(op, number::checked_mul(&v2, &v1), v2, v1);
             }
             OpCode::DIV => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                if v1 != 0 {
-                    self.stack.push(v2 / v1);
+                if !number::is_zero(&v1) {
+                    let q = match self.dialect.division() {
+                        Rounding::Truncate => number::checked_div(&v2, &v1),
+                        Rounding::Floor => number::checked_div_floor(&v2, &v1),
+                    };
+                    self.push_result(op, q, v2, v1);
                 } else {
-                    self.error = Some("Dividing by zero; skipping".to_string());
+                    self.fault(Fault::DivisionByZero { op });
                     self.stack.push(v2);
                     self.stack.push(v1);
                 }
             }
             OpCode::MOD => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                if v1 != 0 {
-                    self.stack.push(v2 % v1);
+                if !number::is_zero(&v1) {
+                    let r = match self.dialect.modulo() {
+                        Rounding::Truncate => number::checked_rem(&v2, &v1),
+                        Rounding::Floor => number::checked_rem_floor(&v2, &v1),
+                    };
+                    self.push_result(op, r, v2, v1);
                 } else {
-                    self.error = Some("Modular arithmetic with zero as base; skipping".to_string());
+                    self.fault(Fault::DivisionByZero { op });
                     self.stack.push(v2);
                     self.stack.push(v1);
                 }
             }
             OpCode::NOT => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
                 let v = self.stack.pop()?;
-                self.stack.push(if v == 0 { 1 } else { 0 });
+                self.stack.push(number::from_i64(if number::is_zero(&v) { 1 } else { 0 }));
             }
             OpCode::GT => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (v1, v2) = (self.stack.pop()?, self.stack.pop()?);
-                self.stack.push(if v2 > v1 { 1 } else { 0 });
+                self.stack.push(number::from_i64(if v2 > v1 { 1 } else { 0 }));
             }
             OpCode::PTR => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
-                let v = self.stack.pop()?;
-                self.dp = rotate_direction(self.dp, v);
+                let v = self.stack.pop()? % number::from_i64(4);
+                self.dp = rotate_direction(self.dp, number::to_i64(&v)? as i32);
             }
             OpCode::SWTCH => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
-                let v = self.stack.pop()?;
-                self.cc = switch_codel(self.cc, v);
+                let v = self.stack.pop()? % number::from_i64(2);
+                self.cc = switch_codel(self.cc, number::to_i64(&v)? as i32);
             }
             OpCode::DUP => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
-                let v = self.stack.pop()?;
-                self.stack.push(v);
+                let v = self.stack.last().cloned()?;
                 self.stack.push(v);
             }
             OpCode::ROLL => {
-                if self.stack.len() < 2 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 2) {
                     return None;
                 }
                 let (num_rolls, n) = (self.stack.pop()?, self.stack.pop()?);
-                if num_rolls < 0 || self.stack.len() < n as usize {
-                    return None;
-                }
-                // Copied from https://github.com/tessi/rpiet/blob/master/src/command.rs#L267,
-                // because I don't know what I am doing with this command
-                let num_rolls = num_rolls % n;
-                let mut substack: Vec<_> = self
-                    .stack
-                    .drain(self.stack.len() - (n as usize)..)
-                    .collect();
-                if num_rolls > 0 {
-                    substack.rotate_right(num_rolls as usize);
-                } else {
-                    substack.rotate_left(-num_rolls as usize);
-                }
-                self.stack.append(&mut substack);
-            }
-            OpCode::INPN => {
-                let mut line: String = "".to_string();
-                let stdin = io::stdin();
-                match stdin.read_line(&mut line) {
-                    Ok(_) => self.stack.push(match line.trim().parse() {
-                        Ok(num) => num,
-                        Err(_) => {
-                            self.error = Some(format!("Couldn't parse input '{}'", line));
-                            return None;
+                let depth = match number::to_i64(&n) {
+                    Some(d) if d >= 0 && self.stack.len() as i64 >= d => d as usize,
+                    _ => {
+                        // Rolling below the bottom of the stack is ignored
+                        let args = [n, num_rolls];
+                        if self.dialect.restores_ignored_roll() {
+                            self.stack.extend_from_slice(&args);
                         }
-                    }),
-                    Err(_) => self.error = Some("Couldn't parse input".to_string()),
+                        let [n, num_rolls] = args;
+                        self.fault(Fault::InvalidRoll { depth: n, rolls: num_rolls });
+                        return None;
+                    }
+                };
+                if depth == 0 {
+                    return None;
                 }
+                // A negative number of rolls goes the other way, which is the same as rolling the
+                // floored remainder forwards
+                let rolls = number::checked_rem_floor(&num_rolls, &n)?;
+                let (at, rolls) = (self.stack.len() - depth, number::to_i64(&rolls)? as usize);
+                self.stack[at..].rotate_right(rolls);
+                self.rolled = Some((depth, rolls));
             }
+            OpCode::INPN => match self.input.read_word(&mut self.io) {
+                Ok(Some(word)) => match word.parse().ok().and_then(|n| self.fit(n)) {
+                    Some(num) => self.stack.push(num),
+                    None => self.fault(Fault::InputParse(word)),
+                },
+                Ok(None) => self.end_of_input(),
+                Err(e) => self.fault(Fault::Input(e.to_string())),
+            },
             OpCode::INPC => {
-                let mut line: String = "".to_string();
-                let stdin = io::stdin();
-                match stdin.read_line(&mut line) {
-                    Ok(_) => self.stack.push(line.as_bytes()[0] as i32),
-                    Err(_) => self.error = Some("Couldn't parse input".to_string()),
+                let c = if self.byte_io {
+                    self.input.read_byte(&mut self.io).map(|c| c.map(u32::from))
+                } else {
+                    self.input.read_char(&mut self.io).map(|c| c.map(u32::from))
+                };
+                match c {
+                    Ok(Some(c)) => self.stack.push(number::from_i64(i64::from(c))),
+                    Ok(None) => self.end_of_input(),
+                    Err(e) => self.fault(Fault::Input(e.to_string())),
                 }
             }
             OpCode::OUTN => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
                 let n = self.stack.pop()?;
-                self.output = Some(n.to_string());
+                self.write_output(n.to_string().as_bytes());
             }
             OpCode::OUTC => {
-                if self.stack.len() < 1 {
-                    self.error = Some("Not enough values to pop; skipping".to_string());
+                if !self.require(op, 1) {
                     return None;
                 }
-                let n = self.stack.pop()?;
-                self.output = Some((n as u8 as char).to_string());
+                if self.byte_io {
+                    let b = number::to_i64(&number::wrap_i32(self.stack.pop()?))? as u8;
+                    self.write_output(&[b]);
+                    return None;
+                }
+                let c = number::to_i64(self.stack.last()?)
+                    .and_then(|c| u32::try_from(c).ok())
+                    .and_then(char::from_u32);
+                match c {
+                    Some(c) => {
+                        self.stack.pop();
+                        self.write_output(c.to_string().as_bytes());
+                    }
+                    None => {
+                        let n = self.stack.last().cloned()?;
+                        self.fault(Fault::InvalidChar(n));
+                    }
+                }
             }
         }
 
         None
     }
+}
 
-    fn get_edges(&self, blk: &Block) -> Vec<Coord> {
-        match self.dp {
-            Direction::Right => {
-                // Greatest x value fixed
-                let (fixed_x, _): Coord = blk.coords.iter().max_by_key(|(x, _)| x).unwrap().clone();
-                blk.coords
-                    .iter()
-                    .filter_map(|(x, y)| {
-                        if fixed_x == *x {
-                            Some((x.clone(), y.clone()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<Coord>>()
-            }
-            Direction::Down => {
-                // Greatest y value fixed
-                let (_, fixed_y): Coord = blk.coords.iter().max_by_key(|(_, y)| y).unwrap().clone();
-                blk.coords
-                    .iter()
-                    .filter_map(|(x, y)| {
-                        if fixed_y == *y {
-                            Some((x.clone(), y.clone()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<Coord>>()
-            }
-            Direction::Left => {
-                // Smallest x value fixed
-                let (fixed_x, _): Coord = blk.coords.iter().min_by_key(|(x, _)| x).unwrap().clone();
-                blk.coords
-                    .iter()
-                    .filter_map(|(x, y)| {
-                        if fixed_x == *x {
-                            Some((x.clone(), y.clone()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<Coord>>()
-            }
-            Direction::Up => {
-                // Smallest y value fixed
-                let (_, fixed_y): Coord = blk.coords.iter().min_by_key(|(_, y)| y).unwrap().clone();
-                blk.coords
-                    .iter()
-                    .filter_map(|(x, y)| {
-                        if fixed_y == *y {
-                            Some((x.clone(), y.clone()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<Coord>>()
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iobackend::BufferIo;
+
+    /// A CPU on a program of two blocks, with `stack` on its stack.
+    fn cpu(dialect: Dialect, stack: &[i64]) -> CPU<BufferIo> {
+        let code = Blocks::from_text("nR lR").unwrap();
+        let cfg = Config { dialect, ..Config::default() };
+        let mut cpu = CPU::new(code, &cfg, BufferIo::new(vec![]));
+        cpu.stack = stack.iter().map(|&n| number::from_i64(n)).collect();
+        cpu
     }
 
-    fn choose_coord(&self, edges: Vec<Coord>) -> Coord {
-        let mut edges = edges;
-        edges.sort_by_key(|(x, y)| match self.dp {
-            Direction::Left | Direction::Right => *y,
-            Direction::Up | Direction::Down => *x,
-        });
-        match self.dp {
-            Direction::Up | Direction::Right => {
-                if self.cc == Direction::Left {
-                    edges.first().unwrap().clone()
-                } else {
-                    edges.last().unwrap().clone()
-                }
-            }
-            Direction::Left | Direction::Down => {
-                if self.cc == Direction::Left {
-                    edges.last().unwrap().clone()
-                } else {
-                    edges.first().unwrap().clone()
-                }
-            }
-        }
+    /// Executes `op` on `stack` from the first block.
+    fn execute(dialect: Dialect, stack: &[i64], op: OpCode) -> CPU<BufferIo> {
+        let mut cpu = cpu(dialect, stack);
+        let blk = cpu.code.get(0).to_viewableblock();
+        cpu.execute(blk, op);
+        cpu
+    }
+
+    fn stack(cpu: &CPU<BufferIo>) -> Vec<i64> {
+        cpu.stack.iter().map(|n| number::to_i64(n).unwrap()).collect()
+    }
+
+    #[test]
+    fn div_truncates_under_spec() {
+        assert_eq!(stack(&execute(Dialect::Spec, &[-7, 2], OpCode::DIV)), [-3]);
+        assert_eq!(stack(&execute(Dialect::Spec, &[7, -2], OpCode::DIV)), [-3]);
+        assert_eq!(stack(&execute(Dialect::Npiet, &[-7, 2], OpCode::DIV)), [-3]);
+    }
+
+    #[test]
+    fn div_floors_under_pietdev() {
+        assert_eq!(stack(&execute(Dialect::PietDev, &[-7, 2], OpCode::DIV)), [-4]);
+        assert_eq!(stack(&execute(Dialect::PietDev, &[7, -2], OpCode::DIV)), [-4]);
+    }
+
+    #[test]
+    fn mod_takes_the_sign_of_the_divisor() {
+        assert_eq!(stack(&execute(Dialect::Spec, &[-7, 3], OpCode::MOD)), [2]);
+        assert_eq!(stack(&execute(Dialect::Spec, &[7, -3], OpCode::MOD)), [-2]);
+        assert_eq!(stack(&execute(Dialect::Rpiet, &[-7, 3], OpCode::MOD)), [-1]);
+    }
+
+    #[test]
+    fn ptr_rotates_counter_clockwise_for_negative_values() {
+        assert_eq!(execute(Dialect::Spec, &[1], OpCode::PTR).dp, Direction::Down);
+        assert_eq!(execute(Dialect::Spec, &[-1], OpCode::PTR).dp, Direction::Up);
+        assert_eq!(execute(Dialect::Spec, &[-5], OpCode::PTR).dp, Direction::Up);
+        assert_eq!(execute(Dialect::Spec, &[-6], OpCode::PTR).dp, Direction::Left);
+    }
+
+    #[test]
+    fn swtch_toggles_for_odd_negative_values() {
+        assert_eq!(execute(Dialect::Spec, &[-1], OpCode::SWTCH).cc, Direction::Right);
+        assert_eq!(execute(Dialect::Spec, &[-2], OpCode::SWTCH).cc, Direction::Left);
+        assert_eq!(execute(Dialect::Spec, &[-3], OpCode::SWTCH).cc, Direction::Right);
+    }
+
+    #[test]
+    fn roll_to_depth_zero_does_nothing() {
+        let cpu = execute(Dialect::Spec, &[1, 2, 0, 3], OpCode::ROLL);
+        assert_eq!(stack(&cpu), [1, 2]);
+        assert!(cpu.error.is_none());
+    }
+
+    #[test]
+    fn roll_with_negative_depth_is_ignored() {
+        let cpu = execute(Dialect::Spec, &[1, 2, -1, 1], OpCode::ROLL);
+        assert_eq!(stack(&cpu), [1, 2, -1, 1]);
+        assert!(matches!(cpu.error.unwrap().fault, Fault::InvalidRoll { .. }));
+        assert_eq!(stack(&execute(Dialect::PietDev, &[1, 2, -1, 1], OpCode::ROLL)), [1, 2]);
+    }
+
+    #[test]
+    fn roll_deeper_than_the_stack_is_ignored() {
+        let cpu = execute(Dialect::Npiet, &[1, 2, 3, 1], OpCode::ROLL);
+        assert_eq!(stack(&cpu), [1, 2, 3, 1]);
+        assert!(matches!(cpu.error.unwrap().fault, Fault::InvalidRoll { .. }));
+        assert_eq!(stack(&execute(Dialect::Rpiet, &[1, 2, 3, 1], OpCode::ROLL)), [1, 2]);
+    }
+
+    #[test]
+    fn roll_buries_the_top_value() {
+        assert_eq!(stack(&execute(Dialect::Spec, &[1, 2, 3, 3, 1], OpCode::ROLL)), [3, 1, 2]);
+        assert_eq!(stack(&execute(Dialect::Spec, &[1, 2, 3, 3, 4], OpCode::ROLL)), [3, 1, 2]);
+    }
+
+    #[test]
+    fn roll_with_negative_count_digs_values_up() {
+        assert_eq!(stack(&execute(Dialect::Spec, &[1, 2, 3, 3, -1], OpCode::ROLL)), [2, 3, 1]);
+        assert_eq!(stack(&execute(Dialect::Spec, &[1, 2, 3, 3, -5], OpCode::ROLL)), [3, 1, 2]);
+    }
+
+    #[test]
+    fn blocked_moves_toggle_the_cc_then_rotate_the_dp() {
+        // Both ways right are blocked, so the CC is toggled to the right, and then the DP is
+        // rotated down, keeping the CC. That picks the left of the bottom edge, and POPs into the
+        // light red block, where toggling the CC back would have PUSHed into the dark one.
+        let code = Blocks::from_text("nR nR KK\nlR dR KK").unwrap();
+        let mut cpu = CPU::new(code, &Config::default(), BufferIo::new(vec![]));
+        cpu.stack.push(number::from_i64(1));
+        assert!(cpu.try_step());
+        assert_eq!((cpu.dp, cpu.cc), (Direction::Down, Direction::Right));
+        assert_eq!(cpu.pc, (0, 1));
+        assert_eq!(cpu.last_cmd, Some(OpCode::POP));
     }
 }
@@ -0,0 +1,92 @@
+//! Interpreters disagree on the corners of Piet that the spec leaves open (or that they got
+//! wrong), so a dialect picks one consistent set of answers, letting programs written against
+//! another interpreter produce the same output here.
+
+use std::str::FromStr;
+
+/// Which way the quotient of a division is rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Towards zero, so the remainder takes the sign of the dividend, like C and Rust
+    Truncate,
+    /// Towards negative infinity, so the remainder takes the sign of the divisor
+    Floor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Follows the spec: MOD takes the sign of the divisor and ignored commands leave the stack
+    /// alone. The spec doesn't say how DIV rounds, so it truncates like npiet
+    Spec,
+    /// npiet, the reference interpreter, which divides like C but floors MOD
+    Npiet,
+    /// PietDev, the online IDE, which floors like JavaScript's `Math.floor` and consumes what it
+    /// can on a stack underflow
+    PietDev,
+    /// rpiet, which uses Rust's operators as-is
+    Rpiet,
+    /// The spec as first published, which stops in white blocks and leaves them by their exits
+    /// like colored blocks, instead of sliding straight through. Older programs like `piet.gif`
+    /// were written against it, and get stuck when sliding
+    Legacy,
+}
+
+impl Dialect {
+    /// How DIV rounds its result.
+    pub fn division(self) -> Rounding {
+        match self {
+            Dialect::PietDev => Rounding::Floor,
+            Dialect::Spec | Dialect::Npiet | Dialect::Rpiet | Dialect::Legacy => Rounding::Truncate,
+        }
+    }
+
+    /// How MOD rounds, which decides the sign of its result.
+    pub fn modulo(self) -> Rounding {
+        match self {
+            Dialect::Spec | Dialect::Npiet | Dialect::PietDev | Dialect::Legacy => Rounding::Floor,
+            Dialect::Rpiet => Rounding::Truncate,
+        }
+    }
+
+    /// Whether a ROLL that gets ignored, because its depth is negative or deeper than the stack,
+    /// puts its arguments back on the stack rather than dropping them.
+    pub fn restores_ignored_roll(self) -> bool {
+        match self {
+            Dialect::Spec | Dialect::Npiet | Dialect::Legacy => true,
+            Dialect::PietDev | Dialect::Rpiet => false,
+        }
+    }
+
+    /// Whether a command that finds too few values on the stack pops the ones there are, rather
+    /// than leaving the stack alone.
+    pub fn underflow_pops(self) -> bool {
+        match self {
+            Dialect::PietDev => true,
+            Dialect::Spec | Dialect::Npiet | Dialect::Rpiet | Dialect::Legacy => false,
+        }
+    }
+
+    /// Whether moving into a white block slides straight through it to the next colored block,
+    /// rather than stopping in it like in any other block.
+    pub fn slides_through_white(self) -> bool {
+        match self {
+            Dialect::Spec | Dialect::Npiet | Dialect::PietDev | Dialect::Rpiet => true,
+            Dialect::Legacy => false,
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dialect, String> {
+        match s {
+            "spec" => Ok(Dialect::Spec),
+            "npiet" => Ok(Dialect::Npiet),
+            "pietdev" => Ok(Dialect::PietDev),
+            "rpiet" => Ok(Dialect::Rpiet),
+            "legacy" => Ok(Dialect::Legacy),
+            _ => Err(format!("Unknown dialect '{}'", s)),
+        }
+    }
+}
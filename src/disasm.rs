@@ -0,0 +1,200 @@
+//! Disassembles a program into its control-flow graph: for every colored block and every
+//! combination of DP and CC, where the PC goes next and which command that executes.
+
+use std::collections::BTreeMap;
+
+use crate::blocks;
+use crate::blocks::{Blocks, Slide, Type};
+use crate::cpu::{Direction, OpCode};
+use crate::utils::Coord;
+
+const DIRECTIONS: [Direction; 4] = [Direction::Right, Direction::Down, Direction::Left, Direction::Up];
+
+/// Where the PC ends up when it leaves a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Obstructed by black or by the edge of the image
+    Blocked,
+    /// Moved into another colored block at `to`, with the given DP and CC. `op` is the command
+    /// that gets executed, which is `None` if the PC slid through white to get there.
+    Block { block: usize, to: Coord, dp: Direction, cc: Direction, op: Option<OpCode> },
+    /// Trapped while sliding through white, which halts the program
+    Trapped,
+}
+
+/// Leaving a block with a given DP and CC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub block: usize,
+    pub dp: Direction,
+    pub cc: Direction,
+    /// The codel the PC leaves from
+    pub from: Coord,
+    pub target: Target,
+}
+
+/// Works out the transitions out of every colored block, 8 per block, in order of block, DP and
+/// then CC.
+pub fn transitions(code: &Blocks) -> Vec<Transition> {
+    let mut transitions = vec![];
+    for i in 0..code.len() {
+        let (l0, h0) = match code.get(i).t {
+            Type::Color(l, h) => (l, h),
+            _ => continue,
+        };
+
+        for &dp in DIRECTIONS.iter() {
+            for &cc in [Direction::Left, Direction::Right].iter() {
+                let exit = code.exit(i, dp, cc);
+                let target = match exit.block.map(|j| (j, code.get(j).t)) {
+                    None | Some((_, Type::Black)) => Target::Blocked,
+                    Some((j, Type::Color(l1, h1))) => Target::Block {
+                        block: j,
+                        to: exit.to,
+                        dp,
+                        cc,
+                        op: Some(OpCode::typeof_exec(l0, h0, l1, h1)),
+                    },
+                    Some((_, Type::White)) => match code.slide(exit.to, dp, cc) {
+                        Slide::Reached(to, dp, cc) => Target::Block {
+                            block: code.find_index(&to).unwrap(),
+                            to,
+                            dp,
+                            cc,
+                            op: None,
+                        },
+                        Slide::Trapped(_, _, _) => Target::Trapped,
+                    },
+                };
+                transitions.push(Transition { block: i, dp, cc, from: exit.from, target });
+            }
+        }
+    }
+    transitions
+}
+
+/// Describes a command, along with the value it pushes for PUSH.
+fn describe_op(code: &Blocks, block: usize, op: OpCode) -> String {
+    match op {
+        OpCode::PUSH => format!("PUSH {}", code.get(block).coords.len()),
+        _ => format!("{:?}", op),
+    }
+}
+
+/// The first colored block the program reaches, sliding there if it starts on white.
+fn start_block(code: &Blocks) -> Option<usize> {
+    let i = code.find_index(&(0, 0))?;
+    match code.get(i).t {
+        Type::Color(_, _) => Some(i),
+        Type::White => match code.slide((0, 0), Direction::Right, Direction::Left) {
+            Slide::Reached(to, _, _) => code.find_index(&to),
+            Slide::Trapped(_, _, _) => None,
+        },
+        Type::Black => None,
+    }
+}
+
+/// A readable listing of every transition, grouped by the block it leaves from.
+pub fn listing(code: &Blocks) -> String {
+    let start = start_block(code);
+    let mut out = String::new();
+    let mut last = None;
+    for t in transitions(code) {
+        if last != Some(t.block) {
+            if last.is_some() {
+                out += "\n";
+            }
+            out += &code.describe(t.block);
+            if start == Some(t.block) {
+                out += " [start]";
+            }
+            out += "\n";
+            last = Some(t.block);
+        }
+
+        let exit = format!("DP {:?}, CC {:?}", t.dp, t.cc);
+        out += &format!("    {:<22} from {:<10} ", exit, format!("{:?}:", t.from));
+        out += &match t.target {
+            Target::Blocked => "blocked".to_string(),
+            Target::Trapped => "trapped in white, halts".to_string(),
+            Target::Block { block, to, op: Some(op), .. } => format!(
+                "{} -> {} at {:?}",
+                describe_op(code, t.block, op),
+                code.describe(block),
+                to
+            ),
+            Target::Block { block, to, dp, cc, op: None } => format!(
+                "through white -> {} at {:?} (DP {:?}, CC {:?})",
+                code.describe(block),
+                to,
+                dp,
+                cc
+            ),
+        };
+        out += "\n";
+    }
+    out
+}
+
+/// The control-flow graph in Graphviz DOT format. Transitions between the same pair of blocks
+/// that execute the same command are merged into one edge, labelled with every DP and CC that
+/// takes it.
+pub fn dot(code: &Blocks) -> String {
+    let short = |d: Direction| format!("{:?}", d)[..1].to_string();
+
+    let mut out = String::from("digraph piet {\n    node [shape=box, style=filled];\n");
+    if let Some(start) = start_block(code) {
+        out += &format!("    start [shape=point];\n    start -> b{};\n", start);
+    }
+
+    let transitions = transitions(code);
+    let mut halts = false;
+    let mut edges: BTreeMap<(usize, String, String), Vec<String>> = BTreeMap::new();
+    for t in &transitions {
+        let (to, label) = match t.target {
+            Target::Blocked => continue,
+            Target::Trapped => ("halt".to_string(), "trapped in white".to_string()),
+            Target::Block { block, op: Some(op), .. } => {
+                (format!("b{}", block), describe_op(code, t.block, op))
+            }
+            Target::Block { block, op: None, .. } => (format!("b{}", block), "white".to_string()),
+        };
+        halts |= to == "halt";
+        edges
+            .entry((t.block, to, label))
+            .or_default()
+            .push(format!("{}/{}", short(t.dp), short(t.cc)));
+    }
+
+    for i in 0..code.len() {
+        let blk = code.get(i);
+        if let Type::Color(_, _) = blk.t {
+            let [r, g, b] = blocks::to_color(blk.t);
+            out += &format!(
+                "    b{} [label=\"{}: {} ({})\", fillcolor=\"#{:02x}{:02x}{:02x}\"];\n",
+                i,
+                i,
+                blk.t,
+                blk.coords.len(),
+                r,
+                g,
+                b
+            );
+
+            // A block that can't be left at all is where the program halts
+            if transitions.iter().filter(|t| t.block == i).all(|t| t.target == Target::Blocked) {
+                out += &format!("    b{} -> halt;\n", i);
+                halts = true;
+            }
+        }
+    }
+    if halts {
+        out += "    halt [shape=doublecircle, style=solid];\n";
+    }
+
+    for ((from, to, label), exits) in edges {
+        out += &format!("    b{} -> {} [label=\"{}\\n{}\"];\n", from, to, label, exits.join(" "));
+    }
+    out += "}\n";
+    out
+}
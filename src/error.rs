@@ -0,0 +1,167 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::blocks::ViewableBlock;
+use crate::cpu::{Direction, OpCode};
+use crate::number::Int;
+use crate::utils::Coord;
+
+/// Something that went wrong while executing a command. Unless noted otherwise, the command is
+/// skipped and the stack is left as it was.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    StackUnderflow { op: OpCode, required: usize, available: usize },
+    DivisionByZero { op: OpCode },
+    /// The result did not fit under the overflow policy
+    Overflow { op: OpCode },
+    /// ROLL with a depth that is negative or deeper than the rest of the stack. Whether the
+    /// arguments are put back depends on the dialect.
+    InvalidRoll { depth: Int, rolls: Int },
+    /// OUTC with a value that is not a unicode scalar value
+    InvalidChar(Int),
+    /// INPN read a word that is not a number. The word is consumed.
+    InputParse(String),
+    EndOfInput,
+    Input(String),
+    Output(String),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackUnderflow { required, available, .. } => write!(
+                f,
+                "needs {} {} on the stack, found {}",
+                required,
+                if *required == 1 { "value" } else { "values" },
+                available
+            ),
+            Fault::DivisionByZero { .. } => write!(f, "division by zero"),
+            Fault::Overflow { .. } => write!(f, "integer overflow"),
+            Fault::InvalidRoll { depth, rolls } => {
+                write!(f, "can't roll {} times to a depth of {}", rolls, depth)
+            }
+            Fault::InvalidChar(n) => write!(f, "{} is not a valid character", n),
+            Fault::InputParse(word) => write!(f, "couldn't parse input '{}'", word),
+            Fault::EndOfInput => write!(f, "reached the end of the input"),
+            Fault::Input(e) => write!(f, "couldn't read input: {}", e),
+            Fault::Output(e) => write!(f, "couldn't write output: {}", e),
+        }
+    }
+}
+
+/// A fault, along with the state of the CPU when it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub fault: Fault,
+    /// The step during which the fault happened, counting from 1
+    pub step: u64,
+    /// The command being executed
+    pub op: Option<OpCode>,
+    /// The block the command is executed from
+    pub block: ViewableBlock,
+    pub pc: Coord,
+    pub dp: Direction,
+    pub cc: Direction,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step {}, ", self.step)?;
+        if let Some(op) = self.op {
+            write!(f, "{:?} ", op)?;
+        }
+        write!(
+            f,
+            "from the {} block of {} {} at {:?} (DP {:?}, CC {:?}): {}",
+            self.block.t,
+            self.block.num,
+            if self.block.num == 1 { "codel" } else { "codels" },
+            self.pc,
+            self.dp,
+            self.cc,
+            self.fault
+        )
+    }
+}
+
+/// A bound on how far a program may run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    /// The most values the stack may hold
+    Stack(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Steps(n) => write!(f, "reached the limit of {} steps", n),
+            Limit::Time(t) => write!(f, "timed out after {:?}", t),
+            Limit::Stack(n) => write!(f, "the stack grew beyond {} values", n),
+        }
+    }
+}
+
+/// A program that was stopped for running into a limit, along with the state it was left in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitError {
+    pub limit: Limit,
+    /// The number of steps taken before stopping
+    pub step: u64,
+    pub block: ViewableBlock,
+    pub pc: Coord,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub stack: Vec<Int>,
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only the top of the stack is shown, since it may be huge
+        const SHOWN: usize = 10;
+
+        write!(
+            f,
+            "step {}, in the {} block of {} {} at {:?} (DP {:?}, CC {:?}): {}\n{} {} on the stack",
+            self.step,
+            self.block.t,
+            self.block.num,
+            if self.block.num == 1 { "codel" } else { "codels" },
+            self.pc,
+            self.dp,
+            self.cc,
+            self.limit,
+            self.stack.len(),
+            if self.stack.len() == 1 { "value" } else { "values" },
+        )?;
+        if !self.stack.is_empty() {
+            write!(f, ":")?;
+            if self.stack.len() > SHOWN {
+                write!(f, " ...")?;
+            }
+            for n in &self.stack[self.stack.len().saturating_sub(SHOWN)..] {
+                write!(f, " {}", n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why a run ended early.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunError {
+    /// A fault in strict mode
+    Fault(RuntimeError),
+    Limit(LimitError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Fault(e) => e.fmt(f),
+            RunError::Limit(e) => e.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! A record of every step a program takes, so that it can be run backwards as well as forwards,
+//! like a debugger stepping back out of a loop that went wrong.
+
+use crate::cpu::{Delta, CPU};
+use crate::iobackend::IoBackend;
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    deltas: Vec<Delta>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// The number of steps that can be undone.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Takes a step, remembering how to undo it. Returns false once the program has halted.
+    pub fn step<I: IoBackend>(&mut self, cpu: &mut CPU<I>) -> bool {
+        let (running, delta) = cpu.step_recorded();
+        // Nothing happens once the program has halted or been stopped
+        if cpu.steps != delta.step {
+            self.deltas.push(delta);
+        }
+        running
+    }
+
+    /// Undoes the last step, returning how many bytes of output it wrote, which the caller has to
+    /// drop. Returns `None` at the start of the program.
+    pub fn back<I: IoBackend>(&mut self, cpu: &mut CPU<I>) -> Option<usize> {
+        let delta = self.deltas.pop()?;
+        cpu.undo(&delta);
+        Some(delta.output)
+    }
+
+    /// Steps backwards or forwards until `step` steps have been taken, or as close as the program
+    /// gets. Returns how many bytes of output the steps taken back wrote.
+    pub fn rewind<I: IoBackend>(&mut self, cpu: &mut CPU<I>, step: u64) -> usize {
+        let mut dropped = 0;
+        while cpu.steps > step {
+            match self.back(cpu) {
+                Some(n) => dropped += n,
+                None => break,
+            }
+        }
+        while cpu.steps < step && self.step(cpu) {}
+        dropped
+    }
+
+    /// The last time the PC was in the block it's in now, before it last entered it, as the number
+    /// of steps taken by then, for rewinding to with `rewind`. Returns `None` if this is the first
+    /// time it's there.
+    pub fn last_time_here<I: IoBackend>(&self, cpu: &CPU<I>) -> Option<u64> {
+        let code = cpu.code();
+        let here = code.find_index(&cpu.pc);
+        self.deltas
+            .iter()
+            .rev()
+            .skip_while(|delta| code.find_index(&delta.pc) == here)
+            .find(|delta| code.find_index(&delta.pc) == here)
+            .map(|delta| delta.step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::blocks::{render, Blocks, UnknownColor};
+    use crate::cpu::{Config, Direction};
+    use crate::iobackend::BufferIo;
+    use crate::number::Int;
+    use crate::utils::Coord;
+
+    type State = (u64, Coord, Direction, Direction, Vec<Int>, usize);
+
+    fn cpu(src: &str, input: &str) -> CPU<BufferIo> {
+        let codels = asm::assemble(src).unwrap();
+        let code = Blocks::from_image(&render(&codels, 1), Some(1), UnknownColor::Error).unwrap();
+        CPU::new(code, &Config::default(), BufferIo::new(input.as_bytes().to_vec()))
+    }
+
+    fn state(cpu: &CPU<BufferIo>, output: &[u8]) -> State {
+        (cpu.steps, cpu.pc, cpu.dp, cpu.cc, cpu.stack.clone(), output.len())
+    }
+
+    /// Runs the program to the end, returning the state before every step and after the last.
+    fn run(cpu: &mut CPU<BufferIo>, history: &mut History, output: &mut Vec<u8>) -> Vec<State> {
+        let mut states = vec![state(cpu, output)];
+        while history.step(cpu) {
+            output.extend(cpu.io_mut().take_output());
+            states.push(state(cpu, output));
+        }
+        output.extend(cpu.io_mut().take_output());
+        states.push(state(cpu, output));
+        states
+    }
+
+    #[test]
+    fn steps_back_through_input_rolls_and_output() {
+        let src = "inpc\ninpc\ninpc\npush 3\npush 1\nroll\noutc\noutc\noutc\n";
+        let mut cpu = cpu(src, "abc");
+        let (mut history, mut output) = (History::new(), vec![]);
+        let mut states = run(&mut cpu, &mut history, &mut output);
+        assert_eq!(output, b"bac");
+
+        // The last step halts without moving, so it has nothing to undo
+        states.pop();
+        while let Some(dropped) = history.back(&mut cpu) {
+            output.truncate(output.len() - dropped);
+            assert_eq!(state(&cpu, &output), states.pop().unwrap());
+        }
+        assert_eq!(cpu.steps, 0);
+        assert!(output.is_empty());
+
+        // Reading the input again gives the same output
+        run(&mut cpu, &mut history, &mut output);
+        assert_eq!(output, b"bac");
+    }
+
+    #[test]
+    fn rewinds_to_any_step() {
+        let src = "push 3\nloop:\ndup\noutn\npush 1\nsub\ndup\njnz loop\n";
+        let mut cpu = cpu(src, "");
+        let (mut history, mut output) = (History::new(), vec![]);
+        let states = run(&mut cpu, &mut history, &mut output);
+        assert_eq!(output, b"321");
+
+        for &step in [5, 0, 9, 2].iter() {
+            let dropped = history.rewind(&mut cpu, step);
+            output.truncate(output.len() - dropped);
+            output.extend(cpu.io_mut().take_output());
+            assert_eq!(state(&cpu, &output), states[step as usize]);
+        }
+    }
+
+    #[test]
+    fn finds_the_last_time_in_a_block() {
+        let src = "push 3\nloop:\ndup\noutn\npush 1\nsub\ndup\njnz loop\n";
+        let mut cpu = cpu(src, "");
+        let (mut history, mut output) = (History::new(), vec![]);
+        let states = run(&mut cpu, &mut history, &mut output);
+        let blocks: Vec<usize> =
+            states.iter().map(|s| cpu.code().find_index(&s.1).unwrap()).collect();
+
+        // Every step in the second time round the loop goes back to the same step in the first
+        let start = (0..).find(|&i| blocks[i + 1..].contains(&blocks[i])).unwrap();
+        let period = (1..).find(|&n| blocks[start + n] == blocks[start]).unwrap();
+        for step in start + period..start + 2 * period {
+            history.rewind(&mut cpu, step as u64);
+            assert_eq!(history.last_time_here(&cpu), Some((step - period) as u64));
+        }
+        history.rewind(&mut cpu, 0);
+        assert_eq!(history.last_time_here(&cpu), None);
+    }
+}
@@ -8,42 +8,79 @@ use tui::Terminal;
 
 use std::io;
 
-use crate::cmdconfig::CmdConfig;
-use crate::cpu::{CPU, OpCode};
-
-pub struct Interpreter {
-    cpu: CPU,
+use piet_tools::blocks::Blocks;
+use piet_tools::coverage::Coverage;
+use piet_tools::cpu::CPU;
+use piet_tools::error::{RunError, RuntimeError};
+use piet_tools::history::History;
+use piet_tools::iobackend::{BufferIo, IoBackend, StdIo};
+use piet_tools::profile::Profile;
+use piet_tools::trace;
+
+use crate::cmdconfig::{load_blocks, CmdConfig};
+
+pub struct Interpreter<I: IoBackend = StdIo> {
+    cpu: CPU<I>,
     filename: String,
+    trace: Option<trace::Format>,
 }
 
-impl Interpreter {
-    pub fn from_config(cfg: &CmdConfig) -> Interpreter {
+impl<I: IoBackend> Interpreter<I> {
+    pub fn new(code: Blocks, cfg: &CmdConfig, io: I) -> Interpreter<I> {
         Interpreter {
-            cpu: CPU::from_config(cfg),
+            cpu: CPU::new(code, &cfg.vm, io),
             filename: cfg.src.to_string(),
+            trace: cfg.trace,
         }
     }
 
-    pub fn run(&mut self) {
-        let mut running = true;
-        while running {
-            running = self.cpu.try_step();
-
-            if let Some(err) = &self.cpu.error {
-                eprintln!("error: {}\n", err);
-            }
+    pub fn from_config(cfg: &CmdConfig, io: I) -> io::Result<Interpreter<I>> {
+        Ok(Interpreter::new(load_blocks(cfg)?, cfg, io))
+    }
 
-            if let Some(output) = &self.cpu.output {
-                print!("{}", output);
-            }
+    /// Runs the program until it halts, reporting faults as they happen, and tracing every step
+    /// to stderr if asked to. See `CPU::run`.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        let on_fault = |err: &RuntimeError| eprintln!("error: {}; skipping\n", err);
+        match self.trace {
+            Some(format) => self.cpu.run_traced(on_fault, |cpu| {
+                if let Some(line) = trace::line(cpu, format) {
+                    eprintln!("{}", line);
+                }
+            }),
+            None => self.cpu.run(on_fault),
         }
     }
 
+    /// Runs the program like `run`, counting every step into a profile of it.
+    pub fn profile(&mut self) -> (Profile, Result<(), RunError>) {
+        let mut profile = Profile::new(self.cpu.code());
+        let result = self.cpu.run_traced(
+            |err| eprintln!("error: {}; skipping\n", err),
+            |cpu| profile.record(&cpu.last_step.unwrap()),
+        );
+        (profile, result)
+    }
+
+    pub fn code(&self) -> &Blocks {
+        self.cpu.code()
+    }
+
+    /// What the program has covered, if the CPU was configured to keep track.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.cpu.coverage.as_ref()
+    }
+
     pub fn info(&self) {
         println!("{}", self.filename);
         print!("{}", self.cpu.get_info());
     }
+}
 
+impl Interpreter<BufferIo> {
+    /// Steps through the program in a terminal UI. Output is collected into its own pane, so the
+    /// program has to be run with an in-memory I/O backend. Every step is recorded, so the program
+    /// can also be stepped backwards, or rewound to any step it has taken.
     pub fn debug(&mut self) -> io::Result<()> {
         let stdout = io::stdout();
         let backend = CrosstermBackend::new(stdout);
@@ -55,8 +92,11 @@ impl Interpreter {
         terminal.clear()?;
 
         let mut running = true;
-        let mut output_buffer: String = String::new();
+        let mut output: Vec<u8> = Vec::new();
         let mut error_buffer: String = String::new();
+        let mut history = History::new();
+        // The step typed in after pressing 'g', until Enter is pressed
+        let mut goto: Option<String> = None;
         // let mut codes: Vec<OpCode> = Vec::new();
         let info_title = format!("Info for {}", self.filename);
 
@@ -73,7 +113,15 @@ impl Interpreter {
                 Text::raw(format!("{:?}\n", self.cpu.pc)),
                 Text::styled("Last Command: ", Style::default().modifier(Modifier::BOLD)),
                 Text::raw(format!("{:?}\n", self.cpu.last_cmd)),
+                Text::styled("Step: ", Style::default().modifier(Modifier::BOLD)),
+                Text::raw(format!("{}\n", self.cpu.steps)),
+                Text::raw(match &goto {
+                    Some(step) => format!("\nGo to step: {}_\n", step),
+                    None => "\nn: next  b: back  l: last time in this block  g: go to step  q: quit\n"
+                        .to_string(),
+                }),
             ];
+            let output_buffer = String::from_utf8_lossy(&output);
 
             // Handle drawing things
             terminal.draw(|mut f| {
@@ -108,37 +156,72 @@ impl Interpreter {
                     .render(&mut f, right_pane[0]);
 
                 // Output space
-                Paragraph::new(vec![Text::raw(output_buffer.as_str())].iter())
+                Paragraph::new([Text::raw(output_buffer.as_ref())].iter())
                     .block(Block::default().borders(Borders::ALL).title("STDOUT"))
                     .render(&mut f, output_panes[0]);
 
                 // Error space
-                Paragraph::new(vec![Text::raw(error_buffer.as_str())].iter())
+                Paragraph::new([Text::raw(error_buffer.as_str())].iter())
                     .block(Block::default().borders(Borders::ALL).title("STDERR"))
                     .render(&mut f, output_panes[1]);
             })?;
 
             // Handle keypresses
-            while let Some(event) = reader.next() {
-                match event {
-                    InputEvent::Keyboard(KeyEvent::Esc)
-                    | InputEvent::Keyboard(KeyEvent::Char('q')) => running = false,
-                    InputEvent::Keyboard(KeyEvent::Char('n')) => {
-                        self.cpu.try_step();
-                    },
+            for event in &mut reader {
+                // Output has to be collected before a step is undone, so that it can be dropped
+                output.extend(self.cpu.io_mut().take_output());
+                let key = match event {
+                    InputEvent::Keyboard(key) => key,
+                    _ => continue,
+                };
+                if let Some(step) = &mut goto {
+                    match key {
+                        KeyEvent::Char(c) if c.is_ascii_digit() => step.push(c),
+                        KeyEvent::Backspace => {
+                            step.pop();
+                        }
+                        KeyEvent::Enter => {
+                            if let Ok(step) = step.parse() {
+                                let dropped = history.rewind(&mut self.cpu, step);
+                                output.truncate(output.len() - dropped);
+                            }
+                            goto = None;
+                        }
+                        KeyEvent::Esc => goto = None,
+                        _ => {}
+                    }
+                    continue;
+                }
+                match key {
+                    KeyEvent::Esc | KeyEvent::Char('q') => running = false,
+                    KeyEvent::Char('n') => {
+                        history.step(&mut self.cpu);
+                    }
+                    KeyEvent::Char('b') => {
+                        if let Some(dropped) = history.back(&mut self.cpu) {
+                            output.truncate(output.len() - dropped);
+                        }
+                    }
+                    KeyEvent::Char('l') => {
+                        if let Some(step) = history.last_time_here(&self.cpu) {
+                            let dropped = history.rewind(&mut self.cpu, step);
+                            output.truncate(output.len() - dropped);
+                        }
+                    }
+                    KeyEvent::Char('g') => goto = Some(String::new()),
                     _ => {}
                 }
             }
 
             // Handle state updates
-            if let Some(out) = &self.cpu.output {
-                output_buffer += out.as_str();
-                self.cpu.output = None;
-            }
+            output.extend(self.cpu.io_mut().take_output());
             if let Some(err) = &self.cpu.error {
-                error_buffer += err.as_str();
+                error_buffer += format!("{}; skipping\n", err).as_str();
                 self.cpu.error = None;
             }
+            if let Some(err) = self.cpu.limit.take() {
+                error_buffer += format!("{}\n", err).as_str();
+            }
         }
 
         Ok(())
@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::str::FromStr;
+
+/// Where the CPU reads its input from and writes its output to.
+pub trait IoBackend {
+    /// Reads a line of input into `buf`, including the trailing newline if there is one. Returns
+    /// the number of bytes read, which is 0 once the input has run out.
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>;
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Buffers the input of an `IoBackend` a line at a time, so that it can be consumed a byte or a
+/// number at a time.
+#[derive(Default)]
+pub struct InputBuffer {
+    pending: VecDeque<u8>,
+    /// The bytes consumed since `record` was called, if it was, so that reads can be taken back
+    recorded: Option<Vec<u8>>,
+}
+
+impl InputBuffer {
+    pub fn new() -> InputBuffer {
+        InputBuffer::default()
+    }
+
+    /// Makes sure there is something to read, returning false at the end of the input.
+    fn fill<I: IoBackend>(&mut self, io: &mut I) -> io::Result<bool> {
+        if self.pending.is_empty() {
+            let mut line = vec![];
+            io.read_line(&mut line)?;
+            self.pending.extend(line);
+        }
+        Ok(!self.pending.is_empty())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        let c = self.pending.pop_front()?;
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push(c);
+        }
+        Some(c)
+    }
+
+    /// Starts keeping the bytes that get consumed, until `take_recorded` is called.
+    pub fn record(&mut self) {
+        self.recorded = Some(vec![]);
+    }
+
+    /// Stops keeping the bytes that get consumed, returning the ones consumed since `record`.
+    pub fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    /// Puts bytes that were consumed back in front of the input, to be read again.
+    pub fn unread(&mut self, bytes: &[u8]) {
+        for &c in bytes.iter().rev() {
+            self.pending.push_front(c);
+        }
+    }
+
+    /// Reads a single byte, including newlines. Returns `None` at the end of the input.
+    pub fn read_byte<I: IoBackend>(&mut self, io: &mut I) -> io::Result<Option<u8>> {
+        if self.fill(io)? {
+            Ok(self.pop())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads a single UTF-8 encoded character, including newlines. Returns `None` at the end of
+    /// the input.
+    pub fn read_char<I: IoBackend>(&mut self, io: &mut I) -> io::Result<Option<char>> {
+        let first = match self.read_byte(io)? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let len = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0,
+        };
+
+        let mut bytes = vec![first];
+        while bytes.len() < len {
+            match self.read_byte(io)? {
+                Some(c) => bytes.push(c),
+                None => break,
+            }
+        }
+        match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+            Some(c) => Ok(Some(c)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid UTF-8 sequence {:x?}", bytes),
+            )),
+        }
+    }
+
+    /// Skips whitespace and reads the next whitespace-separated word. Returns `None` if the input
+    /// runs out before a word starts.
+    pub fn read_word<I: IoBackend>(&mut self, io: &mut I) -> io::Result<Option<String>> {
+        loop {
+            if !self.fill(io)? {
+                return Ok(None);
+            }
+            match self.pending.front() {
+                Some(c) if c.is_ascii_whitespace() => {
+                    self.pop();
+                }
+                _ => break,
+            }
+        }
+
+        let mut word = vec![];
+        while self.fill(io)? {
+            match self.pending.front() {
+                Some(c) if !c.is_ascii_whitespace() => word.extend(self.pop()),
+                _ => break,
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&word).into_owned()))
+    }
+}
+
+/// What reading from the input does once it has run out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eof {
+    /// Push nothing and carry on
+    Nothing,
+    /// Push -1
+    MinusOne,
+    /// Push nothing and report an error
+    Error,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Eof, String> {
+        match s {
+            "nothing" => Ok(Eof::Nothing),
+            "minus-one" => Ok(Eof::MinusOne),
+            "error" => Ok(Eof::Error),
+            _ => Err(format!("Unknown end of input behaviour '{}'", s)),
+        }
+    }
+}
+
+/// Reads from stdin and writes to stdout.
+pub struct StdIo;
+
+impl IoBackend for StdIo {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        // Make sure any prompt shows up before blocking on input
+        io::stdout().flush()?;
+        io::stdin().lock().read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Reads from a fixed buffer and collects the output in memory.
+pub struct BufferIo {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl BufferIo {
+    pub fn new(input: Vec<u8>) -> BufferIo {
+        BufferIo {
+            input: Cursor::new(input),
+            output: vec![],
+        }
+    }
+
+    /// Takes everything written so far, leaving the output empty.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl IoBackend for BufferIo {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.input.read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Reads from and writes to files, falling back to stdin and stdout for whichever is not given.
+pub struct FileIo {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl FileIo {
+    pub fn open(input: Option<&str>, output: Option<&str>) -> io::Result<FileIo> {
+        Ok(FileIo {
+            input: match input {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(BufReader::new(io::stdin())),
+            },
+            output: match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            },
+        })
+    }
+}
+
+impl IoBackend for FileIo {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.output.flush()?;
+        self.input.read_until(b'\n', buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.output.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
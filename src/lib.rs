@@ -0,0 +1,25 @@
+//! An interpreter for the esoteric language Piet.
+//!
+//! A program is loaded into `blocks::Blocks`, from an image file, an encoded image in memory, an
+//! `image::RgbImage` or the plain-text format of the `text` module. It is then run by a
+//! `cpu::CPU`, configured with a `cpu::Config` and doing its input and output through an
+//! `iobackend::IoBackend`. The CPU can either be stepped one block at a time with `try_step`, or
+//! run until it halts with `run`, with every step logged by `trace` or counted by `profile`, and
+//! what it covers collected into a `coverage::Coverage`. A `history::History` records the steps
+//! it takes so they can be undone. The `disasm` module lists a program's control flow without
+//! running it, and the `asm` module paints programs from a list of commands.
+
+pub mod asm;
+pub mod blocks;
+pub mod coverage;
+pub mod cpu;
+pub mod dialect;
+pub mod disasm;
+pub mod error;
+pub mod history;
+pub mod iobackend;
+pub mod number;
+pub mod profile;
+pub mod text;
+pub mod trace;
+pub mod utils;
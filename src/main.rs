@@ -1,41 +1,189 @@
-mod utils;
 mod cmdconfig;
-mod blocks;
-mod cpu;
 mod interpreter;
 
 use clap::{Arg, App, SubCommand, crate_version, crate_authors};
-use cmdconfig::handle_config;
+use cmdconfig::{handle_config, validate_count, validate_seconds, validate_size,
+    validate_unknown_color};
 
 fn main() {
+    // Where to load a program from, which every command that reads one takes
+    let src = Arg::with_name("src")
+        .help("Piet source image or .txt file, or - to read it from stdin")
+        .index(1)
+        .required(true);
+    let size = Arg::with_name("size")
+        .long("size")
+        .help("Width/Height of a codel, in pixels, or 'auto' to detect it from the image")
+        .default_value("auto")
+        .validator(validate_size);
+    let unknown_color = Arg::with_name("unknown-color")
+        .long("unknown-color")
+        .value_name("POLICY")
+        .help("How to treat pixels that are not Piet colors: white, black, error, nearest or nearest:<tolerance>")
+        .default_value("white")
+        .validator(validate_unknown_color);
+
+    let input = Arg::with_name("input")
+        .long("input")
+        .value_name("FILE")
+        .help("Read the program's input from a file instead of stdin (the only input in debug mode)");
+    let output = Arg::with_name("output")
+        .long("output")
+        .value_name("FILE")
+        .help("Write the program's output to a file instead of stdout");
+
+    // The options for running a program, which profiling it and collecting coverage take too
+    let program = SubCommand::with_name("run")
+        .arg(src.clone())
+        .arg(size.clone())
+        .arg(unknown_color.clone())
+        .arg(Arg::with_name("overflow")
+            .long("overflow")
+            .value_name("POLICY")
+            .help("What to do when a result does not fit in 32 bits")
+            .possible_values(&["wrap", "saturate", "trap", "widen"])
+            .default_value("wrap"))
+        .arg(Arg::with_name("dialect")
+            .long("dialect")
+            .value_name("DIALECT")
+            .help("Whose rules to follow for division, MOD, ROLL, stack underflows and white blocks")
+            .possible_values(&["spec", "npiet", "pietdev", "rpiet", "legacy"])
+            .default_value("spec"))
+        .arg(Arg::with_name("eof")
+            .long("eof")
+            .value_name("BEHAVIOUR")
+            .help("What reading input does once the input has run out")
+            .possible_values(&["nothing", "minus-one", "error"])
+            .default_value("nothing"))
+        .arg(Arg::with_name("byte-io")
+            .long("byte-io")
+            .help("Read and write characters as raw bytes instead of UTF-8"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Halt with a non-zero exit code as soon as a command faults, instead of skipping it"))
+        .arg(Arg::with_name("max-steps")
+            .long("max-steps")
+            .validator(validate_count)
+            .value_name("STEPS")
+            .help("Stop the program with exit code 3 after this many steps"))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .validator(validate_seconds)
+            .value_name("SECONDS")
+            .help("Stop the program with exit code 3 once it has run for this long"))
+        .arg(Arg::with_name("max-stack")
+            .long("max-stack")
+            .validator(validate_count)
+            .value_name("VALUES")
+            .help("Stop the program with exit code 3 once the stack holds more than this many values"));
+
+    #[cfg(feature = "bigint")]
+    let program = program.arg(Arg::with_name("bigint")
+        .long("bigint")
+        .help("Use arbitrary-precision integers instead of wrapping around to 32 bits"));
+
+    let profile = program.clone()
+        .name("profile")
+        .arg(input.clone())
+        .arg(output.clone())
+        .arg(Arg::with_name("top")
+            .long("top")
+            .value_name("BLOCKS")
+            .help("How many of the most entered blocks to list")
+            .default_value("20"))
+        .arg(Arg::with_name("heatmap")
+            .long("heatmap")
+            .value_name("FILE")
+            .help("Write an image of the program with its blocks tinted by how often they were entered"))
+        .arg(Arg::with_name("scale")
+            .long("scale")
+            .value_name("PIXELS")
+            .help("Width/Height of a codel in the heatmap, in pixels [default: the program's codel size]"))
+        .about("Run a Piet program, then report to stderr how often each block was entered and each command executed");
+
+    let coverage = program.clone()
+        .name("coverage")
+        .arg(input.clone()
+            .multiple(true)
+            .number_of_values(1)
+            .help("Run the program once for every input file given, instead of once reading stdin"))
+        .arg(Arg::with_name("image")
+            .long("image")
+            .value_name("FILE")
+            .help("Write an image of the program with what was never covered grayed out and every exit marked"))
+        .arg(Arg::with_name("scale")
+            .long("scale")
+            .value_name("PIXELS")
+            .help("Width/Height of a codel in the image, in pixels, with exits marked from 4 up")
+            .default_value("8"))
+        .about("Run a Piet program, then report to stderr which blocks and exits it ever covered");
+
+    let run = program
+        .arg(input)
+        .arg(output)
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .conflicts_with("debug")
+            .help("Log every step to stderr: the blocks moved between, the command and the stack"))
+        .arg(Arg::with_name("trace-json")
+            .long("trace-json")
+            .conflicts_with_all(&["debug", "trace"])
+            .help("Log every step to stderr like --trace, as a JSON object per line"))
+        .arg(Arg::with_name("debug")
+            .short("d")
+            .long("debug")
+            .help("Enter debug mode, where you get to run the program step by step"))
+        .about("Interpret and run a Piet image file");
+
     let matches = App::new("Piet Tools")
         .version(crate_version!())
         .author(crate_authors!())
         .about("A set of tools for the esoteric language Piet")
         .subcommand(SubCommand::with_name("info")
-            .arg(Arg::with_name("src")
-                .help("Piet source image file")
-                .index(1)
+            .arg(src.clone())
+            .arg(size.clone())
+            .arg(unknown_color.clone())
+            .about("Show information about the Piet image file"))
+        .subcommand(SubCommand::with_name("convert")
+            .arg(src.clone())
+            .arg(Arg::with_name("dest")
+                .help("File to write, as text if it ends in .txt or is - for stdout, or as an image otherwise")
+                .index(2)
                 .required(true))
-            .arg(Arg::with_name("size")
-                .long("size")
-                .help("Width/Height of a codel, in pixels")
+            .arg(size.clone())
+            .arg(unknown_color.clone())
+            .arg(Arg::with_name("scale")
+                .long("scale")
+                .value_name("PIXELS")
+                .help("Width/Height of a codel in the written image, in pixels")
                 .default_value("1"))
-            .about("Show information about the Piet image file"))
-        .subcommand(SubCommand::with_name("run")
+            .about("Convert a Piet program between images and text"))
+        .subcommand(SubCommand::with_name("disasm")
+            .arg(src)
+            .arg(size)
+            .arg(unknown_color)
+            .arg(Arg::with_name("dot")
+                .long("dot")
+                .help("Print the control-flow graph in Graphviz DOT format instead of a listing"))
+            .about("List every transition between the blocks of a Piet program"))
+        .subcommand(SubCommand::with_name("asm")
             .arg(Arg::with_name("src")
-                .help("Piet source image file")
+                .help("Assembly source file, with one command per line, or - to read it from stdin")
                 .index(1)
                 .required(true))
-            .arg(Arg::with_name("size")
-                .long("size")
-                .help("Width/Height of a codel, in pixels")
+            .arg(Arg::with_name("dest")
+                .help("File to write, as text if it ends in .txt or is - for stdout, or as an image otherwise")
+                .index(2)
+                .required(true))
+            .arg(Arg::with_name("scale")
+                .long("scale")
+                .value_name("PIXELS")
+                .help("Width/Height of a codel in the written image, in pixels")
                 .default_value("1"))
-            .arg(Arg::with_name("debug")
-                .short("d")
-                .long("debug")
-                .help("Enter debug mode, where you get to run the program step by step"))
-            .about("Interpret and run a Piet image file"))
+            .about("Paint a Piet program from a list of commands, labels and jumps"))
+        .subcommand(run)
+        .subcommand(profile)
+        .subcommand(coverage)
         .get_matches();
 
     handle_config(matches);
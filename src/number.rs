@@ -0,0 +1,171 @@
+//! The integer type used for values on the stack. Values are normally kept within 32 bits (or 64
+//! bits, when widening on overflow), but with the `bigint` feature they are stored as
+//! arbitrary-precision integers and only brought into range when the CPU asks for it.
+
+#[cfg(feature = "bigint")]
+use num_traits::ToPrimitive;
+use std::str::FromStr;
+
+#[cfg(feature = "bigint")]
+pub type Int = num_bigint::BigInt;
+
+#[cfg(not(feature = "bigint"))]
+pub type Int = i64;
+
+#[cfg(feature = "bigint")]
+pub fn from_i64(n: i64) -> Int {
+    Int::from(n)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn from_i64(n: i64) -> Int {
+    n
+}
+
+pub fn is_zero(n: &Int) -> bool {
+    *n == from_i64(0)
+}
+
+/// Converts the value to an `i64`, if it fits.
+#[cfg(feature = "bigint")]
+pub fn to_i64(n: &Int) -> Option<i64> {
+    n.to_i64()
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn to_i64(n: &Int) -> Option<i64> {
+    Some(*n)
+}
+
+/// Wraps the value around into the range of an `i32`, like a two's complement overflow would.
+#[cfg(feature = "bigint")]
+pub fn wrap_i32(n: Int) -> Int {
+    let modulus = from_i64(1 << 32);
+    let n = ((n % &modulus) + &modulus) % &modulus;
+    if n >= from_i64(1 << 31) {
+        n - modulus
+    } else {
+        n
+    }
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn wrap_i32(n: Int) -> Int {
+    i64::from(n as i32)
+}
+
+#[cfg(feature = "bigint")]
+pub fn checked_add(a: &Int, b: &Int) -> Option<Int> {
+    Some(a + b)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn checked_add(a: &Int, b: &Int) -> Option<Int> {
+    a.checked_add(*b)
+}
+
+#[cfg(feature = "bigint")]
+pub fn checked_sub(a: &Int, b: &Int) -> Option<Int> {
+    Some(a - b)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn checked_sub(a: &Int, b: &Int) -> Option<Int> {
+    a.checked_sub(*b)
+}
+
+#[cfg(feature = "bigint")]
+pub fn checked_mul(a: &Int, b: &Int) -> Option<Int> {
+    Some(a * b)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn checked_mul(a: &Int, b: &Int) -> Option<Int> {
+    a.checked_mul(*b)
+}
+
+#[cfg(feature = "bigint")]
+pub fn checked_div(a: &Int, b: &Int) -> Option<Int> {
+    Some(a / b)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn checked_div(a: &Int, b: &Int) -> Option<Int> {
+    a.checked_div(*b)
+}
+
+#[cfg(feature = "bigint")]
+pub fn checked_rem(a: &Int, b: &Int) -> Option<Int> {
+    Some(a % b)
+}
+
+#[cfg(not(feature = "bigint"))]
+pub fn checked_rem(a: &Int, b: &Int) -> Option<Int> {
+    a.checked_rem(*b)
+}
+
+/// Divides, rounding the quotient towards negative infinity instead of towards zero.
+pub fn checked_div_floor(a: &Int, b: &Int) -> Option<Int> {
+    let q = checked_div(a, b)?;
+    let r = checked_rem(a, b)?;
+    if !is_zero(&r) && (r < from_i64(0)) != (*b < from_i64(0)) {
+        checked_sub(&q, &from_i64(1))
+    } else {
+        Some(q)
+    }
+}
+
+/// Takes the remainder of a floored division, which has the sign of the divisor.
+pub fn checked_rem_floor(a: &Int, b: &Int) -> Option<Int> {
+    let r = checked_rem(a, b)?;
+    if !is_zero(&r) && (r < from_i64(0)) != (*b < from_i64(0)) {
+        checked_add(&r, b)
+    } else {
+        Some(r)
+    }
+}
+
+/// What happens when the result of an operation does not fit in 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wrap around, like two's complement arithmetic
+    Wrap,
+    /// Clamp to the smallest or largest 32 bit integer
+    Saturate,
+    /// Refuse to carry out the operation
+    Trap,
+    /// Allow results up to 64 bits, trapping beyond that
+    Widen,
+}
+
+impl Overflow {
+    /// Brings the exact result of an operation into range. Returns `None` if the operation
+    /// should trap instead.
+    pub fn apply(self, n: Int) -> Option<Int> {
+        let min = from_i64(i64::from(i32::MIN));
+        let max = from_i64(i64::from(i32::MAX));
+        match self {
+            Overflow::Wrap => Some(wrap_i32(n)),
+            Overflow::Saturate if n < min => Some(min),
+            Overflow::Saturate if n > max => Some(max),
+            Overflow::Saturate => Some(n),
+            Overflow::Trap if n < min || n > max => None,
+            Overflow::Trap => Some(n),
+            Overflow::Widen => to_i64(&n).map(from_i64),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            "widen" => Ok(Overflow::Widen),
+            _ => Err(format!("Unknown overflow policy '{}'", s)),
+        }
+    }
+}
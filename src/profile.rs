@@ -0,0 +1,114 @@
+//! Profiles a running program, counting how often every block is entered and every command is
+//! executed, to show where it spends its time and which parts of it never run.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use image::RgbImage;
+
+use crate::blocks;
+use crate::blocks::{Blocks, Type};
+use crate::cpu::{OpCode, Step};
+
+/// The color of the coldest and the hottest blocks in a heatmap.
+const COLD: [u8; 3] = [0x00, 0x40, 0xff];
+const HOT: [u8; 3] = [0xff, 0x20, 0x00];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// How many times each block was entered, by index
+    pub hits: Vec<u64>,
+    /// How many times each command was executed
+    pub ops: HashMap<OpCode, u64>,
+    pub steps: u64,
+}
+
+fn mix(a: [u8; 3], b: [u8; 3], t: f64) -> [u8; 3] {
+    let channel = |i: usize| (f64::from(a[i]) * (1.0 - t) + f64::from(b[i]) * t).round() as u8;
+    [channel(0), channel(1), channel(2)]
+}
+
+impl Profile {
+    /// An empty profile of a program, which has only entered the block it starts in.
+    pub fn new(code: &Blocks) -> Profile {
+        let mut hits = vec![0; code.len()];
+        if let Some(start) = code.find_index(&(0, 0)) {
+            hits[start] = 1;
+        }
+        Profile { hits, ops: HashMap::new(), steps: 0 }
+    }
+
+    /// Counts a step taken by the CPU.
+    pub fn record(&mut self, step: &Step) {
+        self.steps += 1;
+        self.hits[step.to] += 1;
+        if let Some(op) = step.op {
+            *self.ops.entry(op).or_default() += 1;
+        }
+    }
+
+    /// A report of the `top` most entered colored blocks and of every command executed, most
+    /// frequent first, along with how many colored blocks were never entered.
+    pub fn report(&self, code: &Blocks, top: usize) -> String {
+        let colored: Vec<usize> = (0..code.len())
+            .filter(|&i| matches!(code.get(i).t, Type::Color(_, _)))
+            .collect();
+        let total: u64 = colored.iter().map(|&i| self.hits[i]).sum();
+
+        let mut out = format!("{} steps\n\nBlocks by times entered:\n", self.steps);
+        let mut ranked: Vec<usize> = colored.iter().copied().filter(|&i| self.hits[i] > 0).collect();
+        ranked.sort_by_key(|&i| (Reverse(self.hits[i]), i));
+        for &i in ranked.iter().take(top) {
+            out += &format!(
+                "{:>12} {:>6.2}%  {}\n",
+                self.hits[i],
+                100.0 * self.hits[i] as f64 / total as f64,
+                code.describe(i)
+            );
+        }
+        if ranked.len() > top {
+            out += &format!("{:>12} more\n", ranked.len() - top);
+        }
+
+        out += "\nCommands by times executed:\n";
+        let mut ops: Vec<(&OpCode, &u64)> = self.ops.iter().collect();
+        ops.sort_by_key(|&(op, n)| (Reverse(*n), format!("{:?}", op)));
+        for (op, n) in ops {
+            out += &format!("{:>12}  {:?}\n", n, op);
+        }
+
+        let dead = colored.len() - ranked.len();
+        out += &format!("\n{} of {} colored blocks were never entered\n", dead, colored.len());
+        out
+    }
+
+    /// Paints the program with every codel `codel_size` pixels wide, tinting the colored blocks
+    /// that were entered from blue to red by how often, on a log scale, and graying out the ones
+    /// that never were.
+    pub fn heatmap(&self, code: &Blocks, codel_size: u32) -> RgbImage {
+        let max = (0..code.len())
+            .filter(|&i| matches!(code.get(i).t, Type::Color(_, _)))
+            .map(|i| self.hits[i])
+            .max()
+            .unwrap_or(0);
+        let scale = (max as f64).ln_1p();
+        let codels = code.codels();
+        let source = code.codel_size();
+
+        let mut img = blocks::render(&codels, codel_size);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (cx, cy) = ((x / codel_size) as i32, (y / codel_size) as i32);
+            let i = code.find_index(&(cx * source, cy * source)).unwrap();
+            if let Type::Color(_, _) = code.get(i).t {
+                pixel.0 = match self.hits[i] {
+                    0 => blocks::dimmed(pixel.0),
+                    n => {
+                        let heat = mix(COLD, HOT, (n as f64).ln_1p() / scale);
+                        mix(pixel.0, heat, 0.6)
+                    }
+                };
+            }
+        }
+        img
+    }
+}
@@ -0,0 +1,163 @@
+//! A plain-text format for programs, where every codel is written as a two letter mnemonic. Colors
+//! are a lightness (`l`ight, `n`ormal or `d`ark) followed by a hue (`R`ed, `Y`ellow, `G`reen,
+//! `C`yan, `B`lue or `M`agenta), while white and black are `WW` and `KK`:
+//!
+//! ```text
+//! # Pushes 2 and prints it
+//! lR lR nR dM
+//! KK KK dM dM
+//! ```
+//!
+//! Codels are separated by whitespace, every line is a row, and everything after a `#` is a
+//! comment. Blank lines are skipped, but all other rows must be the same length.
+
+use std::io;
+
+use crate::blocks::{Hue, Lightness, Type};
+
+/// The mnemonic of a codel, like `nR` for normal red.
+pub fn mnemonic(t: Type) -> String {
+    match t {
+        Type::Color(l, h) => {
+            let l = match l {
+                Lightness::Light => 'l',
+                Lightness::Normal => 'n',
+                Lightness::Dark => 'd',
+            };
+            let h = match h {
+                Hue::Red => 'R',
+                Hue::Yellow => 'Y',
+                Hue::Green => 'G',
+                Hue::Cyan => 'C',
+                Hue::Blue => 'B',
+                Hue::Magenta => 'M',
+            };
+            format!("{}{}", l, h)
+        }
+        Type::White => "WW".to_string(),
+        Type::Black => "KK".to_string(),
+    }
+}
+
+/// Reads a codel back from its mnemonic.
+pub fn from_mnemonic(s: &str) -> Option<Type> {
+    match s {
+        "WW" => return Some(Type::White),
+        "KK" => return Some(Type::Black),
+        _ => {}
+    }
+
+    let mut chars = s.chars();
+    let l = match chars.next()? {
+        'l' => Lightness::Light,
+        'n' => Lightness::Normal,
+        'd' => Lightness::Dark,
+        _ => return None,
+    };
+    let h = match chars.next()? {
+        'R' => Hue::Red,
+        'Y' => Hue::Yellow,
+        'G' => Hue::Green,
+        'C' => Hue::Cyan,
+        'B' => Hue::Blue,
+        'M' => Hue::Magenta,
+        _ => return None,
+    };
+    match chars.next() {
+        None => Some(Type::Color(l, h)),
+        Some(_) => None,
+    }
+}
+
+/// Parses a program into its rows of codels.
+pub fn parse(src: &str) -> io::Result<Vec<Vec<Type>>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut rows: Vec<Vec<Type>> = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let row = line
+            .split_whitespace()
+            .enumerate()
+            .map(|(j, codel)| {
+                from_mnemonic(codel).ok_or_else(|| {
+                    invalid(format!("line {}, codel {}: unknown codel '{}'", i + 1, j + 1, codel))
+                })
+            })
+            .collect::<io::Result<Vec<Type>>>()?;
+
+        if row.is_empty() {
+            continue;
+        }
+        if let Some(first) = rows.first() {
+            if row.len() != first.len() {
+                return Err(invalid(format!(
+                    "line {}: expected {} codels like the first row, found {}",
+                    i + 1,
+                    first.len(),
+                    row.len()
+                )));
+            }
+        }
+        rows.push(row);
+    }
+
+    if rows.is_empty() {
+        return Err(invalid("the program has no codels".to_string()));
+    }
+    Ok(rows)
+}
+
+/// Writes rows of codels out as text, which `parse` reads back.
+pub fn to_text(codels: &[Vec<Type>]) -> String {
+    let mut text = String::new();
+    for row in codels {
+        let row: Vec<String> = row.iter().map(|&t| mnemonic(t)).collect();
+        text += &row.join(" ");
+        text += "\n";
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{Hue, Lightness};
+
+    #[test]
+    fn round_trips_every_codel() {
+        let hues = [Hue::Red, Hue::Yellow, Hue::Green, Hue::Cyan, Hue::Blue, Hue::Magenta];
+        let mut row = vec![Type::White, Type::Black];
+        for &l in [Lightness::Light, Lightness::Normal, Lightness::Dark].iter() {
+            row.extend(hues.iter().map(|&h| Type::Color(l, h)));
+        }
+        let codels = vec![row.clone(), row];
+        assert_eq!(parse(&to_text(&codels)).unwrap(), codels);
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let codels = parse("# A comment\n\nlR WW # another\n\nKK dB\n").unwrap();
+        let blue = Type::Color(Lightness::Dark, Hue::Blue);
+        let red = Type::Color(Lightness::Light, Hue::Red);
+        assert_eq!(codels, vec![vec![red, Type::White], vec![Type::Black, blue]]);
+    }
+
+    #[test]
+    fn rejects_unknown_codels() {
+        let err = parse("lR WW\nKK xY\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2, codel 2: unknown codel 'xY'");
+        assert!(parse("lRR").is_err());
+    }
+
+    #[test]
+    fn rejects_ragged_rows() {
+        let err = parse("lR WW\nKK\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected 2 codels like the first row, found 1");
+    }
+
+    #[test]
+    fn rejects_empty_programs() {
+        assert!(parse("# Nothing here\n\n").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Traces of a running program, with a line for every step like `npiet -t`. Lining up the traces
+//! of two interpreters shows where they start to disagree.
+
+use crate::blocks::Block;
+use crate::cpu::CPU;
+use crate::iobackend::IoBackend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A line of text per step
+    Text,
+    /// A JSON object per line, for other tools to read
+    Json,
+}
+
+fn describe_block(blk: &Block) -> String {
+    format!("{} ({})", blk.t, blk.coords.len())
+}
+
+fn json_block(blk: &Block) -> String {
+    format!("{{\"color\":\"{}\",\"size\":{}}}", blk.t, blk.coords.len())
+}
+
+/// Describes the last step of `cpu`: the step number, where the PC is, the blocks it moved
+/// between, the DP and CC it left by, the command it executed and the stack afterwards, from the
+/// bottom up. If the command turned the DP or CC, the text also says what they are now, while JSON
+/// always has them. Returns `None` if the PC didn't move.
+pub fn line<I: IoBackend>(cpu: &CPU<I>, format: Format) -> Option<String> {
+    let step = cpu.last_step?;
+    let (from, to) = (cpu.code().get(step.from), cpu.code().get(step.to));
+    let stack: Vec<String> = cpu.stack.iter().map(|n| n.to_string()).collect();
+    Some(match format {
+        Format::Text => format!(
+            "step {}: {} -> {} at {:?}, DP {:?}, CC {:?}: {}{}; stack [{}]",
+            cpu.steps,
+            describe_block(from),
+            describe_block(to),
+            cpu.pc,
+            step.dp,
+            step.cc,
+            match step.op {
+                Some(op) => format!("{:?}", op),
+                None => "through white".to_string(),
+            },
+            if (cpu.dp, cpu.cc) != (step.dp, step.cc) {
+                format!(", now DP {:?}, CC {:?}", cpu.dp, cpu.cc)
+            } else {
+                String::new()
+            },
+            stack.join(", ")
+        ),
+        Format::Json => format!(
+            "{{\"step\":{},\"pc\":[{},{}],\"dp\":\"{:?}\",\"cc\":\"{:?}\",\"from\":{},\"to\":{},\
+             \"op\":{},\"next_dp\":\"{:?}\",\"next_cc\":\"{:?}\",\"stack\":[{}]}}",
+            cpu.steps,
+            cpu.pc.0,
+            cpu.pc.1,
+            step.dp,
+            step.cc,
+            json_block(from),
+            json_block(to),
+            match step.op {
+                Some(op) => format!("\"{:?}\"", op),
+                None => "null".to_string(),
+            },
+            cpu.dp,
+            cpu.cc,
+            stack.join(",")
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::Blocks;
+    use crate::cpu::Config;
+    use crate::iobackend::BufferIo;
+
+    /// Pushes 1, then turns the DP down with PTR on the way into the dark cyan block.
+    fn turning() -> CPU<BufferIo> {
+        let code = Blocks::from_text("lR nR dC").unwrap();
+        let mut cpu = CPU::new(code, &Config::default(), BufferIo::new(vec![]));
+        cpu.try_step();
+        cpu.try_step();
+        cpu
+    }
+
+    #[test]
+    fn text_shows_the_dp_and_cc_the_block_was_left_by() {
+        assert_eq!(
+            line(&turning(), Format::Text).unwrap(),
+            "step 2: Normal Red (1) -> Dark Cyan (1) at (2, 0), DP Right, CC Left: PTR, now DP \
+             Down, CC Left; stack []"
+        );
+    }
+
+    #[test]
+    fn json_shows_the_dp_and_cc_before_and_after() {
+        assert_eq!(
+            line(&turning(), Format::Json).unwrap(),
+            "{\"step\":2,\"pc\":[2,0],\"dp\":\"Right\",\"cc\":\"Left\",\
+             \"from\":{\"color\":\"Normal Red\",\"size\":1},\
+             \"to\":{\"color\":\"Dark Cyan\",\"size\":1},\
+             \"op\":\"PTR\",\"next_dp\":\"Down\",\"next_cc\":\"Left\",\"stack\":[]}"
+        );
+    }
+
+    #[test]
+    fn nothing_when_the_pc_did_not_move() {
+        let code = Blocks::from_text("lR").unwrap();
+        let mut cpu = CPU::new(code, &Config::default(), BufferIo::new(vec![]));
+        cpu.try_step();
+        assert_eq!(line(&cpu, Format::Text), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! Runs the sample programs in `examples/` and checks what they print.
+
+use piet_tools::blocks::{Blocks, UnknownColor};
+use piet_tools::cpu::{Config, CPU};
+use piet_tools::dialect::Dialect;
+use piet_tools::error::RunError;
+use piet_tools::iobackend::BufferIo;
+
+/// Runs an example for at most 100,000 steps, returning its output and how it ended.
+fn run(file: &str, dialect: Dialect) -> (String, Result<(), RunError>) {
+    let code = Blocks::from_file(&format!("examples/{}", file), None, UnknownColor::White).unwrap();
+    let cfg = Config { dialect, max_steps: Some(100_000), ..Config::default() };
+    let mut cpu = CPU::new(code, &cfg, BufferIo::new(vec![]));
+    let result = cpu.run(|_| {});
+    let output = String::from_utf8(cpu.io_mut().take_output()).unwrap();
+    (output, result)
+}
+
+#[test]
+fn piet_halts_under_legacy() {
+    let (output, result) = run("piet.gif", Dialect::Legacy);
+    assert_eq!(output, "Piet");
+    assert!(result.is_ok());
+}
+
+#[test]
+fn hello3_halts_under_legacy() {
+    let (output, result) = run("hello3.png", Dialect::Legacy);
+    assert_eq!(output, "Hello, world!\n");
+    assert!(result.is_ok());
+}
+
+#[test]
+fn hello_halts() {
+    // Loops printing "Heo wo" if a blocked move toggles the CC on every attempt
+    let (output, result) = run("hello.png", Dialect::Spec);
+    assert_eq!(output, "Hello world!");
+    assert!(result.is_ok());
+}
+
+#[test]
+fn hello2_halts_under_spec() {
+    let (output, result) = run("hello2.png", Dialect::Spec);
+    assert_eq!(output, "Hello, world!\n");
+    assert!(result.is_ok());
+}
+
+#[test]
+fn hello2_halts_under_legacy() {
+    // hello2.png runs the same under both dialects
+    let (output, result) = run("hello2.png", Dialect::Legacy);
+    assert_eq!(output, "Hello, world!\n");
+    assert!(result.is_ok());
+}
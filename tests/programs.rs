@@ -0,0 +1,63 @@
+//! Runs small programs written in the text format of `piet_tools::text`, and checks what they
+//! print.
+
+use image::RgbImage;
+use piet_tools::blocks::{Blocks, UnknownColor};
+use piet_tools::cpu::{Config, CPU};
+use piet_tools::iobackend::BufferIo;
+
+/// Runs a program for at most 10,000 steps, returning its output and whether it halted.
+fn run(src: &str, input: &str) -> (String, bool) {
+    let code = Blocks::from_text(src).unwrap();
+    let cfg = Config { max_steps: Some(10_000), ..Config::default() };
+    let mut cpu = CPU::new(code, &cfg, BufferIo::new(input.as_bytes().to_vec()));
+    let halted = cpu.run(|_| {}).is_ok();
+    (String::from_utf8(cpu.io_mut().take_output()).unwrap(), halted)
+}
+
+#[test]
+fn pushes_and_prints() {
+    let src = "
+        # Pushes 2 and prints it
+        lR lR nR dM
+        KK KK dM dM
+    ";
+    assert_eq!(run(src, ""), ("2".to_string(), true));
+}
+
+#[test]
+fn slides_through_white() {
+    // Pushes 3 going from light to normal red, slides through the white without executing
+    // anything, then prints going from normal green to dark yellow
+    let src = "
+        lR lR lR nR WW WW nG dY
+        KK KK KK KK KK KK dY dY
+    ";
+    assert_eq!(run(src, ""), ("3".to_string(), true));
+}
+
+#[test]
+fn echoes_a_number() {
+    // Reads a number going from normal cyan to light yellow, and prints it going on to normal red
+    let src = "
+        nC lY lY nR
+        KK KK nR nR
+    ";
+    assert_eq!(run(src, "42\n"), ("42".to_string(), true));
+}
+
+#[test]
+fn halts_at_once_when_starting_on_black() {
+    let src = "
+        KK nR
+        KK nR
+    ";
+    assert_eq!(run(src, ""), ("".to_string(), true));
+}
+
+#[test]
+fn rejects_an_empty_program() {
+    assert!(Blocks::from_text("# Nothing but a comment").is_err());
+    assert!(Blocks::from_image(&RgbImage::new(0, 0), None, UnknownColor::White).is_err());
+    assert!(Blocks::from_image(&RgbImage::new(0, 3), Some(1), UnknownColor::White).is_err());
+}